@@ -1,4 +1,4 @@
-use crate::menu::GameSessionState;
+use crate::menu::{ButtonInteraction, ButtonMaterials, GameSessionState};
 use crate::orientation::{Orientation, PlayerOrientations};
 use crate::{GameState, FPS};
 use bevy::prelude::*;
@@ -6,8 +6,14 @@ use bevy::tasks::IoTaskPool;
 use bevy_ggrs::CommandsExt;
 use ggrs::PlayerType;
 use matchbox_socket::WebRtcNonBlockingSocket;
+use std::collections::VecDeque;
+use std::time::Duration;
 
 const INPUT_SIZE: usize = std::mem::size_of::<u8>();
+const CHAT_LINES: usize = 8;
+const MAX_CHAT_MESSAGE_LENGTH: usize = 200;
+/// How long to wait for the seated players' connections once the start countdown ended
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct LobbyPlugin;
 
@@ -19,7 +25,12 @@ impl Plugin for LobbyPlugin {
                     .with_system(lobby_startup)
                     .with_system(start_matchbox_socket),
             )
-            .add_system_set(SystemSet::on_update(GameState::Lobby).with_system(lobby_system))
+            .add_system_set(
+                SystemSet::on_update(GameState::Lobby)
+                    .with_system(lobby_system)
+                    .with_system(lobby_chat_system)
+                    .with_system(click_ready_button),
+            )
             .add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(lobby_cleanup));
     }
 }
@@ -30,7 +41,17 @@ fn start_matchbox_socket(
     task_pool: Res<IoTaskPool>,
     game_session_state: Res<GameSessionState>,
 ) {
-    let room_url = format!("{}/{}", &args.matchbox, game_session_state.code.clone());
+    let socket = connect_socket(&args, &task_pool, &game_session_state.code);
+    commands.insert_resource(Some(socket));
+}
+
+fn connect_socket(args: &Args, task_pool: &IoTaskPool, code: &str) -> WebRtcNonBlockingSocket {
+    let room_url = format!(
+        "{}/{}?version={}",
+        &args.matchbox,
+        code,
+        env!("CARGO_PKG_VERSION")
+    );
     info!("connecting to matchbox server: {:?}", room_url);
     let (socket, message_loop) = WebRtcNonBlockingSocket::new(room_url);
 
@@ -38,20 +59,40 @@ fn start_matchbox_socket(
     // We do this here using bevy's task system.
     task_pool.spawn(message_loop).detach();
 
-    commands.insert_resource(Some(socket));
+    socket
 }
 
 #[derive(Component)]
 struct LobbyText;
 #[derive(Component)]
 struct LobbyUI;
+#[derive(Component)]
+struct ChatText;
+#[derive(Component)]
+struct ReadyButton;
+#[derive(Component)]
+struct ReadyText;
+
+/// Whether the local player clicked the ready button
+#[derive(Default)]
+struct WantsReady(bool);
+
+/// Messages sent through the matchbox server while waiting for the other players
+#[derive(Default)]
+struct LobbyChat {
+    lines: VecDeque<String>,
+    input: String,
+}
 
 fn lobby_startup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    button_materials: Res<ButtonMaterials>,
     session_state: Res<GameSessionState>,
 ) {
+    commands.insert_resource(LobbyChat::default());
+    commands.insert_resource(WantsReady::default());
     // All this is just for spawning centered text.
     commands.spawn_bundle(UiCameraBundle::default());
     commands
@@ -94,40 +135,193 @@ fn lobby_startup(
                     ..Default::default()
                 })
                 .insert(LobbyText);
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            left: Val::Px(20.),
+                            bottom: Val::Px(20.),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    text: {
+                        let style = TextStyle {
+                            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                            font_size: 32.,
+                            color: Color::BLACK,
+                        };
+                        let mut text = Text::with_section("", style.clone(), Default::default());
+                        text.sections.push(TextSection {
+                            value: "> ".to_string(),
+                            style,
+                        });
+                        text
+                    },
+                    ..Default::default()
+                })
+                .insert(ChatText);
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(160.0), Val::Px(50.0)),
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            right: Val::Px(20.),
+                            bottom: Val::Px(20.),
+                            ..Default::default()
+                        },
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    material: button_materials.normal.clone(),
+                    ..Default::default()
+                })
+                .insert(ReadyButton)
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                "Ready",
+                                TextStyle {
+                                    font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                                    font_size: 40.,
+                                    color: Color::rgb(0.9, 0.9, 0.9),
+                                },
+                                Default::default(),
+                            ),
+                            ..Default::default()
+                        })
+                        .insert(ReadyText);
+                });
         })
         .insert(LobbyUI);
 }
 
 pub struct LocalPlayerHandle(pub usize);
 
+/// Progress of the ready check with the matchbox server
+#[derive(Default)]
+struct ReadyCheck {
+    /// Whether we told the server we are ready
+    ready: bool,
+    /// Countdown once the server started the game
+    start: Option<Timer>,
+    /// Players of the started game, in the order the server decided on
+    seat_order: Vec<String>,
+    /// Time left to connect to every seated player once the countdown ended
+    connect: Option<Timer>,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn lobby_system(
     mut app_state: ResMut<State<GameState>>,
     args: Res<Args>,
     mut socket: ResMut<Option<WebRtcNonBlockingSocket>>,
     mut commands: Commands,
     mut query: Query<&mut Text, With<LobbyText>>,
+    time: Res<Time>,
+    task_pool: Res<IoTaskPool>,
+    game_session_state: Res<GameSessionState>,
+    mut reconnect_timer: Local<Option<Timer>>,
+    mut ready_check: Local<ReadyCheck>,
+    wants_ready: Res<WantsReady>,
 ) {
+    if let Some(timer) = reconnect_timer.as_mut() {
+        if !timer.tick(time.delta()).finished() {
+            return;
+        }
+        *reconnect_timer = None;
+        *ready_check = Default::default();
+        *socket = Some(connect_socket(&args, &task_pool, &game_session_state.code));
+    }
+    // The server is restarting, join the room again once it is back
+    if let Some(reconnect_after) = socket.as_mut().unwrap().server_shutdown() {
+        info!(
+            "Matchbox server is shutting down, reconnecting in {:?}",
+            reconnect_after
+        );
+        query.single_mut().sections[0].value = "Reconnecting...".to_string();
+        *reconnect_timer = Some(Timer::new(reconnect_after, false));
+        // nothing may be sent until the new socket is connected
+        *socket = None;
+        return;
+    }
+
     let socket = socket.as_mut();
 
     socket.as_mut().unwrap().accept_new_connections();
+    for peer in socket.as_mut().unwrap().disconnected_peers() {
+        info!("Peer {:?} left the lobby", peer);
+    }
     let connected_peers = socket.as_ref().unwrap().connected_peers().len() + 1;
-    let remaining = args.players - connected_peers;
-    query.single_mut().sections[0].value = format!("{} connected", connected_peers);
 
-    if remaining > 0 {
-        return;
+    if let Some(start) = socket.as_mut().unwrap().start_game() {
+        info!("The game starts in {:?}", start.start_in);
+        ready_check.start = Some(Timer::new(start.start_in, false));
+        ready_check.seat_order = start.seat_order;
     }
-    info!("All peers have joined, going in-game");
+    match ready_check.start.as_mut() {
+        Some(timer) => {
+            if !timer.tick(time.delta()).finished() {
+                query.single_mut().sections[0].value = "Starting...".to_string();
+                return;
+            }
+        }
+        None => {
+            // ready once clicked and everyone joined, and no longer if someone leaves again
+            let all_joined = connected_peers >= args.players;
+            let ready = wants_ready.0 && all_joined;
+            if ready != ready_check.ready {
+                if let Some(socket) = socket.as_mut() {
+                    // the host seats whoever is there, before the game can start
+                    if ready && socket.is_host() {
+                        socket.assign_seats();
+                    }
+                    socket.ready(ready);
+                }
+                ready_check.ready = ready;
+            }
+            query.single_mut().sections[0].value = format!("{} connected", connected_peers);
+            return;
+        }
+    }
+    // extract final player list, in the seat order the server decided on for everyone
+    let players = match socket
+        .as_mut()
+        .unwrap()
+        .players_in_seat_order(&ready_check.seat_order)
+    {
+        Some(players) => players,
+        None => {
+            // a seated player who never connects (or left) would keep us here forever
+            let connect = ready_check
+                .connect
+                .get_or_insert_with(|| Timer::new(CONNECT_TIMEOUT, false));
+            if connect.tick(time.delta()).finished() {
+                warn!("Couldn't connect to every seated player, back to the menu");
+                *ready_check = Default::default();
+                socket.take();
+                app_state
+                    .set(GameState::Menu)
+                    .expect("Tried to go to the menu while already there");
+                return;
+            }
+            query.single_mut().sections[0].value = "Connecting to players...".to_string();
+            return;
+        }
+    };
+    info!("All peers are ready, going in-game");
+    *ready_check = Default::default();
 
     // consume the socket (currently required because ggrs takes ownership of its socket)
     let socket = socket.take().unwrap();
 
-    // extract final player list
-    let players = socket.players();
-
     // create a GGRS P2P session
     let mut p2p_session =
-        ggrs::P2PSession::new_with_socket(args.players as u32, INPUT_SIZE, socket)
+        ggrs::P2PSession::new_with_socket(players.len() as u32, INPUT_SIZE, socket)
             .expect("failed to start with socket");
 
     // turn on sparse saving
@@ -162,10 +356,90 @@ fn lobby_system(
         .expect("Tried to go in-game while already in-game");
 }
 
+fn lobby_chat_system(
+    mut socket: ResMut<Option<WebRtcNonBlockingSocket>>,
+    mut chat: ResMut<LobbyChat>,
+    mut characters: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    mut query: Query<&mut Text, With<ChatText>>,
+) {
+    // the socket is gone once the game started, or while reconnecting
+    let socket = match socket.as_mut() {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    for event in characters.iter() {
+        if !event.char.is_control() && chat.input.len() < MAX_CHAT_MESSAGE_LENGTH {
+            chat.input.push(event.char);
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        chat.input.pop();
+    }
+    if keys.just_pressed(KeyCode::Return) && !chat.input.trim().is_empty() {
+        let message = std::mem::take(&mut chat.input);
+        socket.broadcast(serde_json::json!({ "chat": message }));
+    }
+
+    let own_id = socket.try_id();
+    for (sender, data) in socket.receive_broadcasts() {
+        let message = match data.get("chat").and_then(|message| message.as_str()) {
+            Some(message) => message,
+            None => continue,
+        };
+        let name = if own_id.as_ref() == Some(&sender) {
+            "You".to_string()
+        } else {
+            sender.chars().take(6).collect()
+        };
+        chat.lines.push_back(format!("{}: {}", name, message));
+        if chat.lines.len() > CHAT_LINES {
+            chat.lines.pop_front();
+        }
+    }
+
+    let mut text = query.single_mut();
+    text.sections[0].value = chat
+        .lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect();
+    text.sections[1].value = format!("> {}", chat.input);
+}
+
+fn click_ready_button(
+    button_materials: Res<ButtonMaterials>,
+    mut wants_ready: ResMut<WantsReady>,
+    mut interaction_query: Query<ButtonInteraction, (Changed<Interaction>, With<ReadyButton>)>,
+    mut text_query: Query<&mut Text, With<ReadyText>>,
+) {
+    for (interaction, mut material) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => {
+                wants_ready.0 = !wants_ready.0;
+                text_query.single_mut().sections[0].value = if wants_ready.0 {
+                    "Not ready".to_string()
+                } else {
+                    "Ready".to_string()
+                };
+            }
+            Interaction::Hovered => {
+                *material = button_materials.hovered.clone();
+            }
+            Interaction::None => {
+                *material = button_materials.normal.clone();
+            }
+        }
+    }
+}
+
 fn lobby_cleanup(query: Query<Entity, With<LobbyUI>>, mut commands: Commands) {
     for e in query.iter() {
         commands.entity(e).despawn_recursive();
     }
+    commands.remove_resource::<LobbyChat>();
+    commands.remove_resource::<WantsReady>();
 }
 
 pub struct Args {
@@ -174,6 +448,13 @@ pub struct Args {
     pub log_filter: String,
 }
 
+impl Args {
+    /// Base url of the matchbox server's http api
+    pub fn http_url(&self) -> String {
+        self.matchbox.replacen("ws", "http", 1)
+    }
+}
+
 impl Default for Args {
     fn default() -> Self {
         Args {
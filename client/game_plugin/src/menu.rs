@@ -1,11 +1,11 @@
 use crate::loading::FontAssets;
+use crate::lobby::Args;
 use crate::GameState;
 use bevy::prelude::*;
-use rand::{thread_rng, Rng};
+use std::sync::{Arc, Mutex};
 
 pub struct MenuPlugin;
 
-const CODE_CHARS: &'static [u8] = b"ABCDEFGHKLMNOPRSTUVWXYZ";
 const ACCEPTED_KEY_INPUT: [KeyCode; 26] = [
     KeyCode::A,
     KeyCode::B,
@@ -41,10 +41,12 @@ impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<ButtonMaterials>()
             .init_resource::<GameSessionState>()
+            .init_resource::<RoomCreation>()
             .add_system_set(SystemSet::on_enter(GameState::Menu).with_system(setup_menu))
             .add_system_set(
                 SystemSet::on_update(GameState::Menu)
                     .with_system(click_new_game_button)
+                    .with_system(room_created)
                     .with_system(listen_for_input),
             )
             .add_system_set(SystemSet::on_exit(GameState::Menu).with_system(clean_menu_ui));
@@ -52,9 +54,9 @@ impl Plugin for MenuPlugin {
 }
 
 #[derive(Component)]
-struct ButtonMaterials {
-    normal: Handle<ColorMaterial>,
-    hovered: Handle<ColorMaterial>,
+pub(crate) struct ButtonMaterials {
+    pub(crate) normal: Handle<ColorMaterial>,
+    pub(crate) hovered: Handle<ColorMaterial>,
 }
 
 impl FromWorld for ButtonMaterials {
@@ -87,6 +89,13 @@ pub struct GameSessionState {
     pub code: String,
 }
 
+/// A room being created on the matchbox server, filled in by the http callback
+#[derive(Default)]
+struct RoomCreation {
+    pending: bool,
+    result: Arc<Mutex<Option<Result<String, String>>>>,
+}
+
 fn setup_menu(
     mut commands: Commands,
     font_assets: Res<FontAssets>,
@@ -184,20 +193,45 @@ fn listen_for_input(
         .for_each(drop);
 }
 
-type ButtonInteraction<'a> = (&'a Interaction, &'a mut Handle<ColorMaterial>);
+pub(crate) type ButtonInteraction<'a> = (&'a Interaction, &'a mut Handle<ColorMaterial>);
 
 fn click_new_game_button(
     button_materials: Res<ButtonMaterials>,
-    mut state: ResMut<State<GameState>>,
-    mut game_session_state: ResMut<GameSessionState>,
+    args: Res<Args>,
+    mut room_creation: ResMut<RoomCreation>,
     mut interaction_query: Query<ButtonInteraction, (Changed<Interaction>, With<NewGameButton>)>,
 ) {
     for (interaction, mut material) in interaction_query.iter_mut() {
         match *interaction {
             Interaction::Clicked => {
-                let code = create_code();
-                game_session_state.code = code;
-                state.set(GameState::Lobby).unwrap();
+                if room_creation.pending {
+                    continue;
+                }
+                room_creation.pending = true;
+                let body = serde_json::json!({
+                    "max": args.players,
+                    "version": env!("CARGO_PKG_VERSION"),
+                });
+                let mut request =
+                    ehttp::Request::post(format!("{}/rooms", args.http_url()), body.to_string());
+                request
+                    .headers
+                    .insert("Content-Type".to_owned(), "application/json".to_owned());
+                let result = room_creation.result.clone();
+                ehttp::fetch(request, move |response| {
+                    let code = response.and_then(|response| {
+                        if !response.ok {
+                            return Err(format!("{} {}", response.status, response.status_text));
+                        }
+                        let room: serde_json::Value = serde_json::from_slice(&response.bytes)
+                            .map_err(|error| error.to_string())?;
+                        room["code"]
+                            .as_str()
+                            .map(str::to_owned)
+                            .ok_or_else(|| "room without a code".to_owned())
+                    });
+                    *result.lock().unwrap() = Some(code);
+                });
             }
             Interaction::Hovered => {
                 *material = button_materials.hovered.clone();
@@ -209,18 +243,28 @@ fn click_new_game_button(
     }
 }
 
+fn room_created(
+    mut room_creation: ResMut<RoomCreation>,
+    mut state: ResMut<State<GameState>>,
+    mut game_session_state: ResMut<GameSessionState>,
+) {
+    let result = room_creation.result.lock().unwrap().take();
+    match result {
+        Some(Ok(code)) => {
+            room_creation.pending = false;
+            game_session_state.code = code;
+            state.set(GameState::Lobby).unwrap();
+        }
+        Some(Err(error)) => {
+            room_creation.pending = false;
+            warn!("Failed to create a room: {}", error);
+        }
+        None => {}
+    }
+}
+
 fn clean_menu_ui(mut commands: Commands, ui_elements: Query<Entity, With<UiElement>>) {
     for entity in ui_elements.iter() {
         commands.entity(entity).despawn_recursive();
     }
 }
-
-fn create_code() -> String {
-    let mut rng = thread_rng();
-    (0..5)
-        .map(|_| {
-            let idx = rng.gen_range(0..CODE_CHARS.len());
-            CODE_CHARS[idx] as char
-        })
-        .collect()
-}
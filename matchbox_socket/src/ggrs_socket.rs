@@ -3,9 +3,10 @@ use std::{
     collections::{hash_map::DefaultHasher, HashMap},
     hash::{Hash, Hasher},
     net::{Ipv6Addr, SocketAddr},
+    time::Duration,
 };
 
-use crate::{webrtc_socket::MessageLoopFuture, WebRtcSocket};
+use crate::{webrtc_socket::MessageLoopFuture, CloseReason, RoomState, StartGame, WebRtcSocket};
 
 #[derive(Debug)]
 pub struct WebRtcNonBlockingSocket {
@@ -45,6 +46,78 @@ impl WebRtcNonBlockingSocket {
         }
     }
 
+    /// Peers that left since the last call
+    pub fn disconnected_peers(&mut self) -> Vec<SocketAddr> {
+        let mut addrs = vec![];
+        for id in self.socket.disconnected_peers() {
+            if let Some(addr) = self.fake_socket_addrs.remove(&id) {
+                self.fake_socket_addrs_reverse.remove(&addr);
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+
+    /// See [`WebRtcSocket::server_shutdown`]
+    pub fn server_shutdown(&mut self) -> Option<Duration> {
+        self.socket.server_shutdown()
+    }
+
+    /// See [`WebRtcSocket::matchmaking_timed_out`]
+    pub fn matchmaking_timed_out(&mut self) -> bool {
+        self.socket.matchmaking_timed_out()
+    }
+
+    /// See [`WebRtcSocket::close_reason`]
+    pub fn close_reason(&mut self) -> Option<&CloseReason> {
+        self.socket.close_reason()
+    }
+
+    /// See [`WebRtcSocket::match_formed`]
+    pub fn match_formed(&mut self) -> Option<&[String]> {
+        self.socket.match_formed()
+    }
+
+    /// See [`WebRtcSocket::try_id`]
+    pub fn try_id(&mut self) -> Option<String> {
+        self.socket.try_id()
+    }
+
+    /// See [`WebRtcSocket::broadcast`]
+    pub fn broadcast(&mut self, data: serde_json::Value) {
+        self.socket.broadcast(data)
+    }
+
+    /// See [`WebRtcSocket::receive_broadcasts`]
+    pub fn receive_broadcasts(&mut self) -> Vec<(String, serde_json::Value)> {
+        self.socket.receive_broadcasts()
+    }
+
+    /// See [`WebRtcSocket::room_state`]
+    pub fn room_state(&mut self) -> &RoomState {
+        self.socket.room_state()
+    }
+
+    /// See [`WebRtcSocket::set_state`]
+    pub fn set_state<T: Into<String>>(&mut self, key: T, value: Option<serde_json::Value>) {
+        self.socket.set_state(key, value)
+    }
+
+    /// See [`WebRtcSocket::set_room_state`]
+    pub fn set_room_state<T: Into<String>>(&mut self, key: T, value: Option<serde_json::Value>) {
+        self.socket.set_room_state(key, value)
+    }
+
+    /// See [`WebRtcSocket::ready`]
+    pub fn ready(&mut self, ready: bool) {
+        self.socket.ready(ready)
+    }
+
+    /// See [`WebRtcSocket::start_game`]
+    pub fn start_game(&mut self) -> Option<StartGame> {
+        self.socket.start_game()
+    }
+
     pub fn connected_peers(&self) -> Vec<SocketAddr> {
         self.socket
             .connected_peers()
@@ -53,18 +126,50 @@ impl WebRtcNonBlockingSocket {
             .collect()
     }
 
-    pub fn players(&self) -> Vec<PlayerType> {
+    /// See [`WebRtcSocket::is_host`]
+    pub fn is_host(&mut self) -> bool {
+        self.socket.is_host()
+    }
+
+    /// See [`WebRtcSocket::assign_seats`]
+    pub fn assign_seats(&mut self) {
+        self.socket.assign_seats()
+    }
+
+    /// Players in the seat order the signalling server decided on, `None` until the room
+    /// is seated, see [`WebRtcSocket::seats`] and [`Self::players_in_seat_order`]
+    pub fn seated_players(&mut self) -> Option<Vec<PlayerType>> {
+        let seats = self.socket.seats()?.to_vec();
+        self.players_in_seat_order(&seats)
+    }
+
+    /// Players in seat order, or sorted by id if the room has not been seated. `None`
+    /// until the signalling server assigned our id
+    pub fn players(&mut self) -> Option<Vec<PlayerType>> {
+        if let Some(players) = self.seated_players() {
+            return Some(players);
+        }
         // needs to be consistent order across all peers
         let mut ids = self.socket.connected_peers();
-        ids.push(self.socket.id().to_owned());
+        ids.push(self.socket.try_id()?);
         ids.sort();
-        ids.iter()
+        self.players_in_seat_order(&ids)
+    }
+
+    /// Players in the order given by the signalling server, see [`StartGame::seat_order`].
+    /// `None` until the signalling server assigned our id and we are connected to every
+    /// other player
+    pub fn players_in_seat_order(&mut self, seat_order: &[String]) -> Option<Vec<PlayerType>> {
+        let own_id = self.socket.try_id()?;
+        seat_order
+            .iter()
             .map(|id| {
-                if id == self.socket.id() {
-                    PlayerType::Local
+                if id == &own_id {
+                    Some(PlayerType::Local)
                 } else {
-                    let addr = self.fake_socket_addrs.get(id).unwrap().clone();
-                    PlayerType::Remote(addr)
+                    self.fake_socket_addrs
+                        .get(id)
+                        .map(|addr| PlayerType::Remote(*addr))
                 }
             })
             .collect()
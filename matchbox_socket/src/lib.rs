@@ -4,4 +4,6 @@ mod webrtc_socket;
 
 #[cfg(feature = "ggrs-socket")]
 pub use ggrs_socket::WebRtcNonBlockingSocket;
-pub use webrtc_socket::WebRtcSocket;
+pub use webrtc_socket::{
+    CloseReason, Encoding, RoomState, ServerError, SignallingOptions, StartGame, WebRtcSocket,
+};
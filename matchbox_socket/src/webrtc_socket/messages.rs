@@ -2,19 +2,133 @@ use serde::{Deserialize, Serialize};
 
 pub(crate) type PeerId = String;
 
+/// A change to the key-value state of the room
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateChange {
+    /// The peer whose namespace the key is in, `None` for room-wide keys
+    pub peer: Option<PeerId>,
+    pub key: String,
+    /// `None` if the key was removed
+    pub value: Option<serde_json::Value>,
+}
+
 /// Events go from signalling server to peer
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PeerEvent {
+    /// Our own id, assigned by the server once we joined the room
+    IdAssigned(PeerId),
+    /// The requested room has no space left, the server closes the connection
+    RoomFull,
+    /// The requested room was created by a client with a different version, the server
+    /// closes the connection
+    VersionMismatch {
+        room_version: Option<String>,
+    },
     NewPeer(PeerId),
-    Signal { sender: PeerId, data: PeerSignal },
+    PeerLeft(PeerId),
+    Signal {
+        sender: PeerId,
+        data: PeerSignal,
+    },
+    /// The server rejected a request, or closes the connection, e.g. for a timeout
+    Error {
+        code: String,
+        message: String,
+    },
+    /// The signalling server is shutting down, reconnect after the given delay
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    /// An admin removed us or closed our room, the server closes the connection
+    Kicked {
+        reason: String,
+    },
+    /// The peer that decides for the room, sent when joining and whenever it changes
+    HostChanged(PeerId),
+    /// Settings chosen by the host, sent when joining and whenever they change
+    RoomSettings(serde_json::Value),
+    /// Data a peer in the room broadcast through the server, including our own
+    Broadcast {
+        sender: PeerId,
+        data: serde_json::Value,
+    },
+    /// Changes to the room state, a snapshot replaces everything known before
+    RoomState {
+        snapshot: bool,
+        changes: Vec<StateChange>,
+    },
+    /// Everyone in the room is ready, start `start_in_ms` after receiving this, with
+    /// the players in `seat_order`
+    StartGame {
+        seat_order: Vec<PeerId>,
+        start_in_ms: u64,
+    },
+    /// No match was found in time, the server closes the connection
+    MatchmakingTimeout,
+    /// Our next_N room was finished with fewer players, because someone waited long enough
+    MatchFormed {
+        peers: Vec<PeerId>,
+    },
+    /// The final seat order of the room, for the game session
+    Seats(Vec<PeerId>),
+    /// A spectator joined the room, sent to players only
+    NewSpectator(PeerId),
+    /// Reconnecting with `?resume=<token>` for a while after losing the connection keeps
+    /// our id and place in the room
+    ResumeToken(String),
+    /// A peer lost its connection to the server and resumed its session
+    PeerReconnected(PeerId),
+}
+
+impl PeerEvent {
+    /// Whether the server ends our session after this event, so there is nothing to resume
+    pub(crate) fn ends_session(&self) -> bool {
+        match self {
+            PeerEvent::RoomFull
+            | PeerEvent::VersionMismatch { .. }
+            | PeerEvent::Kicked { .. }
+            | PeerEvent::MatchmakingTimeout
+            | PeerEvent::ServerShutdown { .. } => true,
+            // peers that timed out can resume, like those whose connection broke
+            PeerEvent::Error { code, .. } => {
+                matches!(
+                    code.as_str(),
+                    "RoomExpired" | "RateLimited" | "TooManyConnections"
+                )
+            }
+            _ => false,
+        }
+    }
 }
 
 // TODO: move back into lib
 /// Requests go from peer to signalling server
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PeerRequest {
-    Uuid(PeerId),
-    Signal { receiver: PeerId, data: PeerSignal },
+    Signal {
+        receiver: PeerId,
+        data: PeerSignal,
+    },
+    /// Host only: makes another peer in the room the host
+    TransferHost(PeerId),
+    /// Host only: settings all peers in the room should use
+    SetRoomSettings(serde_json::Value),
+    /// Sends data to everyone in the room through the server
+    Broadcast(serde_json::Value),
+    /// Sets a key in our own namespace of the room state, `None` removes it
+    SetState {
+        key: String,
+        value: Option<serde_json::Value>,
+    },
+    /// Host only: sets a room-wide key of the room state, `None` removes it
+    SetRoomState {
+        key: String,
+        value: Option<serde_json::Value>,
+    },
+    /// Whether we are ready to start the game
+    Ready(bool),
+    /// Host only: seats everyone in the room in the order they joined
+    AssignSeats,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -1,8 +1,8 @@
-use std::pin::Pin;
+use std::{collections::BTreeMap, pin::Pin, time::Duration};
 
-use futures::{Future, FutureExt, StreamExt};
+use futures::{pin_mut, stream::FusedStream, Future, FutureExt, StreamExt};
 use futures_util::select;
-use log::debug;
+use log::{debug, warn};
 
 mod messages;
 mod signal_peer;
@@ -30,17 +30,135 @@ use native::*;
 use wasm::*;
 
 use messages::*;
-use uuid::Uuid;
 
 type Packet = Box<[u8]>;
 
+/// Sent by the signalling server once everyone in the room is ready
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartGame {
+    /// Every player in the room, including ourselves, in the same order for everyone
+    pub seat_order: Vec<PeerId>,
+    /// How long to wait before starting, counting from when the server's message
+    /// arrived, e.g. with a timer started as soon as [`WebRtcSocket::start_game`]
+    /// returns it
+    pub start_in: Duration,
+}
+
+/// Why the signalling server closed our connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The room we asked for has no space left
+    RoomFull,
+    /// The room was created by a client with a different version, `None` if it didn't
+    /// give one
+    VersionMismatch { room_version: Option<String> },
+    /// An admin removed us or closed the room
+    Kicked { reason: String },
+}
+
+/// An error the signalling server reported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    /// The server's name for the error, e.g. `Throttled` or `RoomExpired`
+    pub code: String,
+    pub message: String,
+}
+
+/// How requests and events are encoded on the websocket to the signalling server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Text frames, understood by every signalling server
+    #[default]
+    Json,
+    /// Binary frames, more compact for SDP-heavy signals. Falls back to JSON if the
+    /// signalling server doesn't support it
+    MessagePack,
+}
+
+impl Encoding {
+    /// The websocket subprotocol the signalling server knows the encoding by
+    fn protocol(self) -> Option<&'static str> {
+        match self {
+            Encoding::Json => None,
+            Encoding::MessagePack => Some("msgpack"),
+        }
+    }
+
+    /// The encoding to use, given the protocol the signalling server accepted
+    fn negotiated(self, accepted_protocol: Option<&str>) -> Self {
+        match self.protocol() {
+            Some(protocol) if accepted_protocol == Some(protocol) => self,
+            _ => Encoding::Json,
+        }
+    }
+}
+
+/// How to connect to the signalling server
+#[derive(Debug, Clone, Default)]
+pub struct SignallingOptions {
+    pub encoding: Encoding,
+    /// DER encoded certificates to trust for `wss://` urls, e.g. the self-signed
+    /// certificate of a development server. The webpki roots are trusted if empty.
+    /// Browsers only trust their own certificate store
+    #[cfg(not(target_arch = "wasm32"))]
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+/// Key-value state of the room, kept in sync by the signalling server
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomState {
+    /// Keys for the whole room, only the host can set them
+    pub room: BTreeMap<String, serde_json::Value>,
+    /// Keys each peer set for itself
+    pub peers: BTreeMap<PeerId, BTreeMap<String, serde_json::Value>>,
+}
+
+impl RoomState {
+    fn apply(&mut self, change: StateChange) {
+        let keys = match &change.peer {
+            Some(peer) => self.peers.entry(peer.clone()).or_default(),
+            None => &mut self.room,
+        };
+        match change.value {
+            Some(value) => {
+                keys.insert(change.key, value);
+            }
+            None => {
+                keys.remove(&change.key);
+            }
+        }
+        if let Some(peer) = &change.peer {
+            if self.peers.get(peer).is_some_and(BTreeMap::is_empty) {
+                self.peers.remove(peer);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WebRtcSocket {
     messages_from_peers: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
     new_connected_peers: futures_channel::mpsc::UnboundedReceiver<PeerId>,
+    disconnected_peers: futures_channel::mpsc::UnboundedReceiver<PeerId>,
     peer_messages_out: futures_channel::mpsc::Sender<(PeerId, Packet)>,
     peers: Vec<PeerId>,
-    id: PeerId,
+    id_rx: futures_channel::mpsc::UnboundedReceiver<PeerId>,
+    id: Option<PeerId>,
+    server_events_rx: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
+    server_shutdown: Option<Duration>,
+    matchmaking_timed_out: bool,
+    close_reason: Option<CloseReason>,
+    errors: Vec<ServerError>,
+    match_formed: Option<Vec<PeerId>>,
+    seats: Option<Vec<PeerId>>,
+    host: Option<PeerId>,
+    room_settings: Option<serde_json::Value>,
+    broadcasts: Vec<(PeerId, serde_json::Value)>,
+    new_spectators: Vec<PeerId>,
+    resume_token: Option<String>,
+    room_state: RoomState,
+    start_game: Option<StartGame>,
+    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -52,27 +170,74 @@ pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = ()>>>;
 impl WebRtcSocket {
     #[must_use]
     pub fn new<T: Into<String>>(room_url: T) -> (Self, MessageLoopFuture) {
+        Self::new_with_encoding(room_url, Encoding::Json)
+    }
+
+    /// Like [`WebRtcSocket::new`], but talks to the signalling server in the given encoding
+    #[must_use]
+    pub fn new_with_encoding<T: Into<String>>(
+        room_url: T,
+        encoding: Encoding,
+    ) -> (Self, MessageLoopFuture) {
+        Self::new_with_options(
+            room_url,
+            SignallingOptions {
+                encoding,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`WebRtcSocket::new`], with options for connecting to the signalling server
+    #[must_use]
+    pub fn new_with_options<T: Into<String>>(
+        room_url: T,
+        options: SignallingOptions,
+    ) -> (Self, MessageLoopFuture) {
         let (messages_from_peers_tx, messages_from_peers) = futures_channel::mpsc::unbounded();
         let (new_connected_peers_tx, new_connected_peers) = futures_channel::mpsc::unbounded();
+        let (disconnected_peers_tx, disconnected_peers) = futures_channel::mpsc::unbounded();
+        let (id_tx, id_rx) = futures_channel::mpsc::unbounded();
+        let (server_events_tx, server_events_rx) = futures_channel::mpsc::unbounded();
+        let (requests_sender, requests_receiver) = futures_channel::mpsc::unbounded();
         let (peer_messages_out_tx, peer_messages_out_rx) =
             futures_channel::mpsc::channel::<(PeerId, Packet)>(32);
 
-        // Would perhaps be smarter to let signalling server decide this...
-        let id = Uuid::new_v4().to_string();
-
         (
             Self {
-                id: id.clone(),
+                id_rx,
+                id: None,
+                server_events_rx,
+                server_shutdown: None,
+                matchmaking_timed_out: false,
+                close_reason: None,
+                errors: vec![],
+                match_formed: None,
+                seats: None,
+                host: None,
+                room_settings: None,
+                broadcasts: vec![],
+                new_spectators: vec![],
+                resume_token: None,
+                room_state: Default::default(),
+                start_game: None,
+                requests_sender: requests_sender.clone(),
                 messages_from_peers,
                 peer_messages_out: peer_messages_out_tx,
                 new_connected_peers,
+                disconnected_peers,
                 peers: vec![],
             },
             Box::pin(run_socket(
                 room_url.into(),
-                id,
+                options,
+                id_tx,
+                server_events_tx,
+                requests_sender,
+                requests_receiver,
                 peer_messages_out_rx,
                 new_connected_peers_tx,
+                disconnected_peers_tx,
                 messages_from_peers_tx,
             )),
         )
@@ -101,6 +266,16 @@ impl WebRtcSocket {
         ids
     }
 
+    /// Peers that left the room since the last call, they are no longer connected
+    pub fn disconnected_peers(&mut self) -> Vec<PeerId> {
+        let mut ids = Vec::new();
+        while let Ok(Some(id)) = self.disconnected_peers.try_next() {
+            self.peers.retain(|peer| peer != &id);
+            ids.push(id);
+        }
+        ids
+    }
+
     pub fn connected_peers(&self) -> Vec<PeerId> {
         self.peers.clone() // TODO: could probably be an iterator or reference instead?
     }
@@ -122,49 +297,386 @@ impl WebRtcSocket {
             .expect("send_to failed");
     }
 
-    pub fn id(&self) -> &PeerId {
-        &self.id
+    /// Our own id, resolves once the signalling server assigned it
+    pub async fn id(&mut self) -> PeerId {
+        if self.id.is_none() {
+            self.id = Some(self.id_rx.next().await.expect("Signal server died"));
+        }
+        self.id.clone().unwrap()
+    }
+
+    /// Our own id, if the signalling server assigned it already
+    pub fn try_id(&mut self) -> Option<PeerId> {
+        if self.id.is_none() {
+            if let Ok(Some(id)) = self.id_rx.try_next() {
+                self.id = Some(id);
+            }
+        }
+        self.id.clone()
+    }
+
+    /// Set once the signalling server announced that it is shutting down, with how long
+    /// to wait before connecting a new socket. Peers that are connected already stay
+    /// connected, but no new peers can join until then
+    pub fn server_shutdown(&mut self) -> Option<Duration> {
+        self.receive_server_events();
+        self.server_shutdown
+    }
+
+    /// Whether the signalling server gave up finding a match, see the `rating` and
+    /// `max_wait` query parameters of next_N rooms. No peers will join, connect a new
+    /// socket to try again
+    pub fn matchmaking_timed_out(&mut self) -> bool {
+        self.receive_server_events();
+        self.matchmaking_timed_out
+    }
+
+    /// Set if the signalling server refused to let us join or closed our connection
+    /// later. No new peers will join, peers that are connected already stay connected
+    pub fn close_reason(&mut self) -> Option<&CloseReason> {
+        self.receive_server_events();
+        self.close_reason.as_ref()
+    }
+
+    /// Errors the signalling server reported since the last call, e.g. for requests it
+    /// rejected or right before it closes the connection
+    pub fn receive_errors(&mut self) -> Vec<ServerError> {
+        self.receive_server_events();
+        std::mem::take(&mut self.errors)
+    }
+
+    /// The peers in our next_N room, including ourselves, if the signalling server
+    /// finished it with fewer players than requested, see the `max_wait` and
+    /// `min_players` query parameters. No more peers will join
+    pub fn match_formed(&mut self) -> Option<&[PeerId]> {
+        self.receive_server_events();
+        self.match_formed.as_deref()
+    }
+
+    /// The peer that decides for the room, may be ourselves. `None` until the room has
+    /// one, i.e. while waiting for a next_N room to fill up
+    pub fn host(&mut self) -> Option<PeerId> {
+        self.receive_server_events();
+        self.host.clone()
+    }
+
+    pub fn is_host(&mut self) -> bool {
+        let id = self.try_id();
+        id.is_some() && self.host() == id
+    }
+
+    /// Makes another peer in the room the host, only works while we are the host
+    pub fn transfer_host<T: Into<PeerId>>(&mut self, peer: T) {
+        self.send_request(PeerRequest::TransferHost(peer.into()));
+    }
+
+    /// Settings chosen by the host, e.g. the map to play on
+    pub fn room_settings(&mut self) -> Option<serde_json::Value> {
+        self.receive_server_events();
+        self.room_settings.clone()
+    }
+
+    /// Shares settings with everyone in the room, only works while we are the host
+    pub fn set_room_settings(&mut self, settings: serde_json::Value) {
+        self.send_request(PeerRequest::SetRoomSettings(settings));
+    }
+
+    /// Sends data to everyone in the room, ourselves included, through the signalling
+    /// server. Works before any peer-to-peer connection exists, e.g. for lobby chat,
+    /// but is subject to the server's rate limits and size caps
+    pub fn broadcast(&mut self, data: serde_json::Value) {
+        self.send_request(PeerRequest::Broadcast(data));
+    }
+
+    /// Broadcasts received since the last call, with the id of their sender
+    pub fn receive_broadcasts(&mut self) -> Vec<(PeerId, serde_json::Value)> {
+        self.receive_server_events();
+        std::mem::take(&mut self.broadcasts)
+    }
+
+    /// Spectators that joined the room since the last call. Join with `?role=spectator`
+    /// to watch a room instead of playing in it
+    pub fn new_spectators(&mut self) -> Vec<PeerId> {
+        self.receive_server_events();
+        std::mem::take(&mut self.new_spectators)
+    }
+
+    /// The key-value state of the room as far as we know it
+    pub fn room_state(&mut self) -> &RoomState {
+        self.receive_server_events();
+        &self.room_state
+    }
+
+    /// Sets a key in our own namespace of the room state, `None` removes it.
+    /// The change shows up in [`WebRtcSocket::room_state`] once the server confirmed it
+    pub fn set_state<T: Into<String>>(&mut self, key: T, value: Option<serde_json::Value>) {
+        self.send_request(PeerRequest::SetState {
+            key: key.into(),
+            value,
+        });
+    }
+
+    /// Sets a room-wide key of the room state, only works while we are the host
+    pub fn set_room_state<T: Into<String>>(&mut self, key: T, value: Option<serde_json::Value>) {
+        self.send_request(PeerRequest::SetRoomState {
+            key: key.into(),
+            value,
+        });
+    }
+
+    /// Tells the signalling server whether we are ready to start the game. The game
+    /// starts once everyone in the room is ready, see [`WebRtcSocket::start_game`]
+    pub fn ready(&mut self, ready: bool) {
+        self.send_request(PeerRequest::Ready(ready));
+    }
+
+    /// Set once everyone in the room is ready. Returns each start only once
+    pub fn start_game(&mut self) -> Option<StartGame> {
+        self.receive_server_events();
+        self.start_game.take()
+    }
+
+    /// Seats everyone in the room in the order they joined, only works while we are the
+    /// host. next_N rooms are seated by the server once they are formed
+    pub fn assign_seats(&mut self) {
+        self.send_request(PeerRequest::AssignSeats);
+    }
+
+    /// The seat order the signalling server decided on, including ourselves. `None` until
+    /// the room is seated, see [`WebRtcSocket::assign_seats`]
+    pub fn seats(&mut self) -> Option<&[PeerId]> {
+        self.receive_server_events();
+        self.seats.as_deref()
+    }
+
+    /// Lets us keep our id and place in the room if the connection to the signalling
+    /// server drops. The socket resumes its session by itself, peers stay connected
+    /// meanwhile. A new socket can take the session over with `?resume=<token>` added to
+    /// the room url
+    pub fn resume_token(&mut self) -> Option<&str> {
+        self.receive_server_events();
+        self.resume_token.as_deref()
+    }
+
+    /// Requests are dropped once the connection to the signalling server is gone
+    fn send_request(&mut self, request: PeerRequest) {
+        if let Err(e) = self.requests_sender.unbounded_send(request) {
+            warn!(
+                "dropping request, not connected to the signalling server: {:?}",
+                e.into_inner()
+            );
+        }
+    }
+
+    /// Applies events about the room the message loop passed on
+    fn receive_server_events(&mut self) {
+        while let Ok(Some(event)) = self.server_events_rx.try_next() {
+            match event {
+                PeerEvent::ServerShutdown { reconnect_after_ms } => {
+                    self.server_shutdown = Some(Duration::from_millis(reconnect_after_ms));
+                }
+                PeerEvent::MatchmakingTimeout => self.matchmaking_timed_out = true,
+                PeerEvent::RoomFull => self.close_reason = Some(CloseReason::RoomFull),
+                PeerEvent::VersionMismatch { room_version } => {
+                    self.close_reason = Some(CloseReason::VersionMismatch { room_version });
+                }
+                PeerEvent::Kicked { reason } => {
+                    self.close_reason = Some(CloseReason::Kicked { reason });
+                }
+                PeerEvent::Error { code, message } => {
+                    warn!("signalling server error {}: {}", code, message);
+                    self.errors.push(ServerError { code, message });
+                }
+                PeerEvent::MatchFormed { peers } => self.match_formed = Some(peers),
+                PeerEvent::Seats(seats) => self.seats = Some(seats),
+                PeerEvent::NewSpectator(id) => self.new_spectators.push(id),
+                PeerEvent::ResumeToken(token) => self.resume_token = Some(token),
+                // our connection to them doesn't go through the server
+                PeerEvent::PeerReconnected(_) => {}
+                PeerEvent::HostChanged(host) => self.host = Some(host),
+                PeerEvent::RoomSettings(settings) => self.room_settings = Some(settings),
+                PeerEvent::Broadcast { sender, data } => self.broadcasts.push((sender, data)),
+                PeerEvent::StartGame {
+                    seat_order,
+                    start_in_ms,
+                } => {
+                    self.start_game = Some(StartGame {
+                        seat_order,
+                        start_in: Duration::from_millis(start_in_ms),
+                    });
+                }
+                PeerEvent::RoomState { snapshot, changes } => {
+                    if snapshot {
+                        self.room_state = Default::default();
+                    }
+                    for change in changes {
+                        self.room_state.apply(change);
+                    }
+                }
+                event => warn!("unexpected server event {:?}", event),
+            }
+        }
+    }
+}
+
+/// How often to try resuming our session after losing the connection to the signalling
+/// server, before giving up on it. Servers keep sessions for 10 seconds by default
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+#[cfg(target_arch = "wasm32")]
+async fn sleep(duration: Duration) {
+    gloo_timers::future::TimeoutFuture::new(duration.as_millis() as u32).await
+}
+
+/// What we know about our session with the signalling server, to resume it
+#[derive(Debug, Default)]
+struct Session {
+    id: Option<PeerId>,
+    resume_token: Option<String>,
+}
+
+impl Session {
+    /// Keeps track of the session. Returns false if the server took us for a new peer
+    /// when resuming, because the session expired in the meantime
+    fn update(&mut self, event: &PeerEvent) -> bool {
+        match event {
+            PeerEvent::IdAssigned(id) => match &self.id {
+                Some(our_id) if our_id != id => {
+                    self.resume_token = None;
+                    return false;
+                }
+                _ => self.id = Some(id.clone()),
+            },
+            PeerEvent::ResumeToken(token) => self.resume_token = Some(token.clone()),
+            event if event.ends_session() => self.resume_token = None,
+            _ => {}
+        }
+        true
     }
 }
 
+/// The signalling protocol we talk, servers treat clients that don't say so as legacy
+/// clients that pick their own id
+const PROTOCOL_VERSION: u32 = 2;
+
+fn with_query_param(room_url: &str, param: &str) -> String {
+    let separator = if room_url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", room_url, separator, param)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_socket(
     room_url: String,
-    id: PeerId,
+    options: SignallingOptions,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    server_events_tx: futures_channel::mpsc::UnboundedSender<PeerEvent>,
+    requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
+    requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
     peer_messages_out_rx: futures_channel::mpsc::Receiver<(PeerId, Packet)>,
     new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    disconnected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
 ) {
     debug!("Starting WebRtcSocket message loop");
 
-    let (requests_sender, requests_receiver) = futures_channel::mpsc::unbounded::<PeerRequest>();
     let (events_sender, events_receiver) = futures_channel::mpsc::unbounded::<PeerEvent>();
 
     let message_loop_fut = message_loop(
-        id,
+        id_tx,
+        server_events_tx,
         requests_sender,
         events_receiver,
         peer_messages_out_rx,
         new_connected_peers_tx,
+        disconnected_peers_tx,
         messages_from_peers_tx,
     );
 
-    let signalling_loop_fut = signalling_loop(room_url, requests_receiver, events_sender);
-
+    let room_url = with_query_param(&room_url, &format!("protocol={}", PROTOCOL_VERSION));
     let mut message_loop_done = Box::pin(message_loop_fut.fuse());
-    let mut signalling_loop_done = Box::pin(signalling_loop_fut.fuse());
+    let mut requests_receiver = requests_receiver;
+    let mut session = Session::default();
+    let mut failed_reconnects = 0;
     loop {
+        let url = match &session.resume_token {
+            Some(token) => with_query_param(&room_url, &format!("resume={}", token)),
+            None => room_url.clone(),
+        };
+        let (connection_events_tx, mut connection_events) = futures_channel::mpsc::unbounded();
+        let mut expired = false;
+        let connected = {
+            let connection = signalling_loop(
+                url,
+                options.clone(),
+                &mut requests_receiver,
+                connection_events_tx,
+            )
+            .fuse();
+            pin_mut!(connection);
+            loop {
+                select! {
+                    _ = message_loop_done => {
+                        debug!("Message loop completed");
+                        return;
+                    }
+                    connected = connection => break connected,
+                    event = connection_events.select_next_some() => {
+                        if !session.update(&event) {
+                            expired = true;
+                            break true;
+                        }
+                        let _ = events_sender.unbounded_send(event);
+                    }
+                }
+            }
+        };
+        debug!("Signalling loop completed");
+        if !expired {
+            // events that arrived just before the connection ended
+            while let Ok(Some(event)) = connection_events.try_next() {
+                if !session.update(&event) {
+                    expired = true;
+                    break;
+                }
+                let _ = events_sender.unbounded_send(event);
+            }
+        }
+        if expired {
+            warn!("Our session with the signalling server expired, not resuming it");
+        }
+
+        if requests_receiver.is_terminated() {
+            // the socket was dropped
+            break;
+        }
+        if connected {
+            failed_reconnects = 0;
+        } else if session.id.is_none() {
+            warn!("Couldn't connect to the signalling server");
+            break;
+        } else {
+            failed_reconnects += 1;
+        }
+        // peers we are connected to already stay connected either way
+        if session.resume_token.is_none() || failed_reconnects > RECONNECT_ATTEMPTS {
+            message_loop_done.await;
+            debug!("Message loop completed");
+            break;
+        }
+        // give the network a moment to come back
         select! {
             _ = message_loop_done => {
                 debug!("Message loop completed");
                 break;
             }
-
-            _ = signalling_loop_done => {
-                debug!("Signalling loop completed");
-                // todo!{"reconnect?"}
-            }
-
-            complete => break
+            _ = sleep(RECONNECT_DELAY).fuse() => {}
         }
+        debug!("Resuming our session with the signalling server");
     }
 }
@@ -27,40 +27,44 @@ use crate::webrtc_socket::{
     Packet,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_loop(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    server_events_tx: futures_channel::mpsc::UnboundedSender<PeerEvent>,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
     peer_messages_out_rx: futures_channel::mpsc::Receiver<(PeerId, Packet)>,
     new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    disconnected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
 ) {
     message_loop_impl(
-        id,
+        id_tx,
+        server_events_tx,
         requests_sender,
         events_receiver,
         peer_messages_out_rx,
         new_connected_peers_tx,
+        disconnected_peers_tx,
         messages_from_peers_tx,
     )
     .compat()
     .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn message_loop_impl(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    server_events_tx: futures_channel::mpsc::UnboundedSender<PeerEvent>,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
     mut peer_messages_out_rx: futures_channel::mpsc::Receiver<(PeerId, Packet)>,
     new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    disconnected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
 ) {
     debug!("Entering native WebRtcSocket message loop");
 
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
-
     // let mut offer_handshakes = FuturesUnordered::new();
     // let mut accept_handshakes = FuturesUnordered::new();
     let mut peer_loops_a = FuturesUnordered::new();
@@ -70,7 +74,8 @@ async fn message_loop_impl(
     let mut connected_peers = HashMap::new();
 
     loop {
-        let next_signal_event = events_receiver.next().fuse();
+        // Stays pending once disconnected from the signalling server
+        let next_signal_event = events_receiver.select_next_some();
         let next_peer_message_out = peer_messages_out_rx.next().fuse();
 
         pin_mut!(next_signal_event, next_peer_message_out);
@@ -96,46 +101,60 @@ async fn message_loop_impl(
                 //     todo!{};
                 },
 
-                message = next_signal_event => {
-                    match message {
-                        Some(event) => {
-                            debug!("{:?}", event);
-                            match event {
-                                PeerEvent::NewPeer(peer_uuid) => {
-                                    let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
-                                    handshake_signals.insert(peer_uuid.clone(), signal_sender);
-                                    let signal_peer = SignalPeer::new(peer_uuid.clone(), requests_sender.clone());
-                                    let handshake_fut = handshake_offer(signal_peer, signal_receiver).compat();
-                                    let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
-                                    connected_peers.insert(peer_uuid, to_peer_data_tx);
-                                    peer_loops_a.push(peer_loop(handshake_fut, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), to_peer_data_rx));
-                                }
-                                PeerEvent::Signal { sender, data } => {
-                                    let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
-                                        let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
-                                        let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
-                                        // We didn't start signalling with this peer, assume we're the accepting part
-                                        let handshake_fut = handshake_accept(signal_peer, from_peer_receiver).compat();
-                                        let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
-                                        connected_peers.insert(sender, to_peer_data_tx);
-                                        let peer_loop_fut = peer_loop(handshake_fut, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), to_peer_data_rx);
-                                        peer_loops_b.push(peer_loop_fut);
-                                        from_peer_sender
-                                    });
-                                    from_peer_sender.unbounded_send(data)
-                                        .expect("failed to forward signal to handshaker");
-                                }
-                            }
-                        },
-                        None => {} // Disconnected from signalling server
-                    };
+                event = next_signal_event => {
+                    debug!("{:?}", event);
+                    match event {
+                        PeerEvent::IdAssigned(id) => {
+                            id_tx.unbounded_send(id).expect("send failed");
+                        }
+                        PeerEvent::NewPeer(peer_uuid) => {
+                            let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+                            handshake_signals.insert(peer_uuid.clone(), signal_sender);
+                            let signal_peer = SignalPeer::new(peer_uuid.clone(), requests_sender.clone());
+                            let handshake_fut = handshake_offer(signal_peer, signal_receiver).compat();
+                            let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
+                            connected_peers.insert(peer_uuid, to_peer_data_tx);
+                            peer_loops_a.push(peer_loop(handshake_fut, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), to_peer_data_rx));
+                        }
+                        PeerEvent::Signal { sender, data } => {
+                            let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
+                                let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
+                                let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
+                                // We didn't start signalling with this peer, assume we're the accepting part
+                                let handshake_fut = handshake_accept(signal_peer, from_peer_receiver).compat();
+                                let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
+                                connected_peers.insert(sender, to_peer_data_tx);
+                                let peer_loop_fut = peer_loop(handshake_fut, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), to_peer_data_rx);
+                                peer_loops_b.push(peer_loop_fut);
+                                from_peer_sender
+                            });
+                            from_peer_sender.unbounded_send(data)
+                                .expect("failed to forward signal to handshaker");
+                        }
+                        PeerEvent::PeerLeft(peer_uuid) => {
+                            // Dropping the senders ends the handshake or peer loop
+                            handshake_signals.remove(&peer_uuid);
+                            connected_peers.remove(&peer_uuid);
+                            disconnected_peers_tx.unbounded_send(peer_uuid).expect("send failed");
+                        }
+                        // Events about the room, kept track of by the socket
+                        event => {
+                            server_events_tx.unbounded_send(event).expect("send failed");
+                        }
+                    }
                 }
 
                 // TODO: maybe use some forward trait instead?
                 message = next_peer_message_out => {
-                    let message = message.unwrap();
-                    let sender = &connected_peers.get(&message.0).unwrap();
-                    sender.unbounded_send(message.1).unwrap();
+                    let message = match message {
+                        Some(message) => message,
+                        // The socket was dropped
+                        None => break,
+                    };
+                    match connected_peers.get(&message.0) {
+                        Some(sender) => sender.unbounded_send(message.1).unwrap(),
+                        None => warn!("Dropping message to disconnected peer {:?}", message.0),
+                    }
                 }
 
                 complete => break
@@ -327,7 +346,14 @@ async fn peer_loop(
     from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
     mut to_peer_message_rx: UnboundedReceiver<Packet>,
 ) {
-    let (peer_id, data_channel) = handshake_fut.compat().await.unwrap();
+    let (peer_id, data_channel) = match handshake_fut.compat().await {
+        Ok(peer) => peer,
+        Err(e) => {
+            // most likely the peer left in the middle of the handshake
+            warn!("handshake failed: {:?}", e);
+            return;
+        }
+    };
     debug!(
         "peer_loop: sending new_peer, data channel state: {:?}",
         data_channel.ready_state()
@@ -351,5 +377,8 @@ async fn peer_loop(
         data_channel.send(&message).compat().await.unwrap();
     }
 
-    // TODO: clear on_message?
+    // The peer left
+    if let Err(e) = data_channel.close().compat().await {
+        warn!("failed to close data channel: {:?}", e);
+    }
 }
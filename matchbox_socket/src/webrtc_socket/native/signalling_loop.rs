@@ -1,19 +1,47 @@
-use async_tungstenite::{async_std::connect_async, tungstenite::Message};
+use async_tls::TlsConnector;
+use async_tungstenite::{
+    async_std::connect_async_with_tls_connector,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
 use futures::{pin_mut, FutureExt, SinkExt, StreamExt};
 use futures_util::select;
 use log::{debug, warn};
 
-use crate::webrtc_socket::messages::{PeerEvent, PeerRequest};
+use crate::webrtc_socket::{
+    messages::{PeerEvent, PeerRequest},
+    Encoding, SignallingOptions,
+};
 
+/// Talks to the signalling server until the connection ends. Returns false if it
+/// couldn't connect
 pub async fn signalling_loop(
     room_url: String,
-    mut requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    options: SignallingOptions,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
     events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
-) {
+) -> bool {
     debug!("Signalling loop started");
-    let (mut wsio, _response) = connect_async(&room_url)
-        .await
-        .expect("failed to connect to signalling server");
+    let encoding = options.encoding;
+    let mut request = room_url.into_client_request().expect("invalid room url");
+    if let Some(protocol) = encoding.protocol() {
+        request
+            .headers_mut()
+            .insert("sec-websocket-protocol", HeaderValue::from_static(protocol));
+    }
+    let connector = tls_connector(&options.root_certificates);
+    let (mut wsio, response) = match connect_async_with_tls_connector(request, connector).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("failed to connect to signalling server: {:?}", e);
+            return false;
+        }
+    };
+    let accepted_protocol = response
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|protocol| protocol.to_str().ok());
+    let encoding = encoding.negotiated(accepted_protocol);
+    debug!("Talking {:?} to the signalling server", encoding);
 
     loop {
         let next_request = requests_receiver.next().fuse();
@@ -23,31 +51,79 @@ pub async fn signalling_loop(
 
         select! {
             request = next_request => {
-                let request = serde_json::to_string(&request).expect("serializing request");
-                debug!("-> {}", request);
-                wsio.send(Message::Text(request)).await.expect("request send error");
+                let request = match request {
+                    Some(request) => request,
+                    // the socket was dropped
+                    None => break,
+                };
+                let message = match encoding {
+                    Encoding::Json => {
+                        let request = serde_json::to_string(&request).expect("serializing request");
+                        debug!("-> {}", request);
+                        Message::Text(request)
+                    }
+                    Encoding::MessagePack => {
+                        debug!("-> {:?}", request);
+                        Message::Binary(rmp_serde::to_vec_named(&request).expect("serializing request"))
+                    }
+                };
+                if let Err(e) = wsio.send(message).await {
+                    warn!("lost the connection to the signalling server: {:?}", e);
+                    break;
+                }
             }
 
             message = next_websocket_message => {
                 match message {
                     Some(Ok(Message::Text(message))) => {
                         debug!("{}", message);
-                        let event: PeerEvent = serde_json::from_str(&message)
-                            .expect(&format!("couldn't parse peer event {}", message));
-                        events_sender.unbounded_send(event).unwrap();
+                        match serde_json::from_str::<PeerEvent>(&message) {
+                            Ok(event) => events_sender.unbounded_send(event).unwrap(),
+                            // e.g. errors, or events this version doesn't know about yet
+                            Err(_) => warn!("ignoring unhandled peer event {}", message),
+                        }
+                    },
+                    Some(Ok(Message::Binary(message))) if encoding == Encoding::MessagePack => {
+                        match rmp_serde::from_slice::<PeerEvent>(&message) {
+                            Ok(event) => {
+                                debug!("{:?}", event);
+                                events_sender.unbounded_send(event).unwrap()
+                            }
+                            // e.g. errors, or events this version doesn't know about yet
+                            Err(_) => warn!("ignoring unhandled peer event {:?}", message),
+                        }
                     },
                     Some(Ok(message)) => {
-                        warn!("ignoring unexpected non-text message from signalling server: {:?}", message)
+                        warn!("ignoring unexpected message from signalling server: {:?}", message)
                     },
                     Some(Err(e)) => {
-                        // TODO: propagate errors or recover
-                        panic!("WebSocket error {:?}", e)
+                        warn!("lost the connection to the signalling server: {:?}", e);
+                        break;
                     },
-                    None => {} // Disconnected from signalling server
+                    None => {
+                        debug!("Disconnected from signalling server");
+                        break;
+                    }
                 };
             }
 
             complete => break
         }
     }
+    true
+}
+
+/// A connector trusting only `root_certificates`, `None` for the default roots
+fn tls_connector(root_certificates: &[Vec<u8>]) -> Option<TlsConnector> {
+    if root_certificates.is_empty() {
+        return None;
+    }
+    let mut config = rustls::ClientConfig::new();
+    for certificate in root_certificates {
+        config
+            .root_store
+            .add(&rustls::Certificate(certificate.clone()))
+            .expect("invalid root certificate");
+    }
+    Some(config.into())
 }
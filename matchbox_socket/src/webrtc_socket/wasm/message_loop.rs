@@ -18,81 +18,97 @@ use crate::webrtc_socket::{
     Packet,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_loop(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    server_events_tx: futures_channel::mpsc::UnboundedSender<PeerEvent>,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
     mut peer_messages_out_rx: futures_channel::mpsc::Receiver<(PeerId, Packet)>,
     new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    disconnected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
 ) {
     debug!("Entering WebRtcSocket message loop");
 
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
-
     let mut offer_handshakes = FuturesUnordered::new();
     let mut accept_handshakes = FuturesUnordered::new();
     let mut handshake_signals = HashMap::new();
     let mut data_channels: HashMap<PeerId, RtcDataChannel> = HashMap::new();
 
     loop {
-        let next_signal_event = events_receiver.next().fuse();
+        // Stays pending once disconnected from the signalling server
+        let next_signal_event = events_receiver.select_next_some();
         let next_peer_message_out = peer_messages_out_rx.next().fuse();
 
         pin_mut!(next_signal_event, next_peer_message_out);
 
         select! {
             res = offer_handshakes.select_next_some() => {
-                check(&res);
-                let peer = res.unwrap();
-                data_channels.insert(peer.0.clone(), peer.1.clone());
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+                if let Some(peer) = check(res) {
+                    data_channels.insert(peer.0.clone(), peer.1.clone());
+                    debug!("Notifying about new peer");
+                    new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+                }
             },
             res = accept_handshakes.select_next_some() => {
                 // TODO: this could be de-duplicated
-                check(&res);
-                let peer = res.unwrap();
-                data_channels.insert(peer.0.clone(), peer.1.clone());
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+                if let Some(peer) = check(res) {
+                    data_channels.insert(peer.0.clone(), peer.1.clone());
+                    debug!("Notifying about new peer");
+                    new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+                }
             },
 
-            message = next_signal_event => {
-                match message {
-                    Some(event) => {
-                        debug!("{:?}", event);
-
-                        match event {
-                            PeerEvent::NewPeer(peer_uuid) => {
-                                let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
-                                handshake_signals.insert(peer_uuid.clone(), signal_sender);
-                                let signal_peer = SignalPeer::new(peer_uuid, requests_sender.clone());
-                                offer_handshakes.push(handshake_offer(signal_peer, signal_receiver, messages_from_peers_tx.clone()));
-                            }
-                            PeerEvent::Signal { sender, data } => {
-                                let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
-                                    let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
-                                    let signal_peer = SignalPeer::new(sender, requests_sender.clone());
-                                    // We didn't start signalling with this peer, assume we're the accepting part
-                                    accept_handshakes.push(handshake_accept(signal_peer, from_peer_receiver, messages_from_peers_tx.clone()));
-                                    from_peer_sender
-                                });
-                                from_peer_sender.unbounded_send(data)
-                                    .expect("failed to forward signal to handshaker");
-                            }
+            event = next_signal_event => {
+                debug!("{:?}", event);
+
+                match event {
+                    PeerEvent::IdAssigned(id) => {
+                        id_tx.unbounded_send(id).expect("send failed");
+                    }
+                    PeerEvent::NewPeer(peer_uuid) => {
+                        let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
+                        handshake_signals.insert(peer_uuid.clone(), signal_sender);
+                        let signal_peer = SignalPeer::new(peer_uuid, requests_sender.clone());
+                        offer_handshakes.push(handshake_offer(signal_peer, signal_receiver, messages_from_peers_tx.clone()));
+                    }
+                    PeerEvent::Signal { sender, data } => {
+                        let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
+                            let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
+                            let signal_peer = SignalPeer::new(sender, requests_sender.clone());
+                            // We didn't start signalling with this peer, assume we're the accepting part
+                            accept_handshakes.push(handshake_accept(signal_peer, from_peer_receiver, messages_from_peers_tx.clone()));
+                            from_peer_sender
+                        });
+                        from_peer_sender.unbounded_send(data)
+                            .expect("failed to forward signal to handshaker");
+                    }
+                    PeerEvent::PeerLeft(peer_uuid) => {
+                        // Dropping the sender ends a handshake that is still going on
+                        handshake_signals.remove(&peer_uuid);
+                        if let Some(data_channel) = data_channels.remove(&peer_uuid) {
+                            data_channel.close();
                         }
-                    },
-                    None => {} // Disconnected from signalling server
-                };
+                        disconnected_peers_tx.unbounded_send(peer_uuid).expect("send failed");
+                    }
+                    // Events about the room, kept track of by the socket
+                    event => {
+                        server_events_tx.unbounded_send(event).expect("send failed");
+                    }
+                }
             }
 
             message = next_peer_message_out => {
-                let message = message.unwrap();
-                let data_channel = data_channels.get(&message.0).expect("couldn't find data channel for peer");
-                data_channel.send_with_u8_array(&message.1).expect("failed to send");
+                let message = match message {
+                    Some(message) => message,
+                    // The socket was dropped
+                    None => break,
+                };
+                match data_channels.get(&message.0) {
+                    Some(data_channel) => data_channel.send_with_u8_array(&message.1).expect("failed to send"),
+                    None => warn!("Dropping message to disconnected peer {:?}", message.0),
+                }
             }
 
             complete => break
@@ -350,10 +366,19 @@ fn create_data_channel(
     channel
 }
 
-// Expect/unwrap is broken in select for some reason :/
-fn check(res: &Result<(PeerId, RtcDataChannel), Box<dyn std::error::Error>>) {
+// Matching on the result is broken in select for some reason :/
+fn check(
+    res: Result<(PeerId, RtcDataChannel), Box<dyn std::error::Error>>,
+) -> Option<(PeerId, RtcDataChannel)> {
     // but doing it inside a typed function works fine
-    res.as_ref().expect("handshake failed");
+    match res {
+        Ok(peer) => Some(peer),
+        Err(e) => {
+            // most likely the peer left in the middle of the handshake
+            warn!("handshake failed: {:?}", e);
+            None
+        }
+    }
 }
 
 // The bellow is just to wrap Result<JsValue, JsValue> into something sensible-ish
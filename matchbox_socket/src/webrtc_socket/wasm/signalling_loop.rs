@@ -1,17 +1,28 @@
-use crate::webrtc_socket::messages::*;
+use crate::webrtc_socket::{messages::*, Encoding, SignallingOptions};
 use futures::{pin_mut, FutureExt, SinkExt, StreamExt};
 use futures_util::select;
-use log::{debug, error};
+use log::{debug, error, warn};
 use ws_stream_wasm::{WsMessage, WsMeta};
 
+/// Talks to the signalling server until the connection ends. Returns false if it
+/// couldn't connect
 pub async fn signalling_loop(
     room_url: String,
-    mut requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    options: SignallingOptions,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
     events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
-) {
-    let (_ws, mut wsio) = WsMeta::connect(&room_url, None)
-        .await
-        .expect("failed to connect to signalling server");
+) -> bool {
+    let encoding = options.encoding;
+    let protocols = encoding.protocol().map(|protocol| vec![protocol]);
+    let (ws, mut wsio) = match WsMeta::connect(&room_url, protocols).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("failed to connect to signalling server: {:?}", e);
+            return false;
+        }
+    };
+    let encoding = encoding.negotiated(Some(&ws.protocol()));
+    debug!("Talking {:?} to the signalling server", encoding);
 
     loop {
         let next_request = requests_receiver.next().fuse();
@@ -21,27 +32,60 @@ pub async fn signalling_loop(
 
         select! {
             request = next_request => {
-                let request = serde_json::to_string(&request).expect("serializing request");
-                debug!("-> {}", request);
-                wsio.send(WsMessage::Text(request)).await.expect("request send error");
+                let request = match request {
+                    Some(request) => request,
+                    // the socket was dropped
+                    None => break,
+                };
+                let message = match encoding {
+                    Encoding::Json => {
+                        let request = serde_json::to_string(&request).expect("serializing request");
+                        debug!("-> {}", request);
+                        WsMessage::Text(request)
+                    }
+                    Encoding::MessagePack => {
+                        debug!("-> {:?}", request);
+                        WsMessage::Binary(rmp_serde::to_vec_named(&request).expect("serializing request"))
+                    }
+                };
+                if let Err(e) = wsio.send(message).await {
+                    warn!("lost the connection to the signalling server: {:?}", e);
+                    break;
+                }
             }
 
             message = next_websocket_message => {
                 match message {
                     Some(WsMessage::Text(message)) => {
                         debug!("{}", message);
-                        let event: PeerEvent = serde_json::from_str(&message)
-                            .expect(&format!("couldn't parse peer event {}", message));
-                        events_sender.unbounded_send(event).unwrap();
+                        match serde_json::from_str::<PeerEvent>(&message) {
+                            Ok(event) => events_sender.unbounded_send(event).unwrap(),
+                            // e.g. errors, or events this version doesn't know about yet
+                            Err(_) => warn!("ignoring unhandled peer event {}", message),
+                        }
+                    },
+                    Some(WsMessage::Binary(message)) if encoding == Encoding::MessagePack => {
+                        match rmp_serde::from_slice::<PeerEvent>(&message) {
+                            Ok(event) => {
+                                debug!("{:?}", event);
+                                events_sender.unbounded_send(event).unwrap()
+                            }
+                            // e.g. errors, or events this version doesn't know about yet
+                            Err(_) => warn!("ignoring unhandled peer event {:?}", message),
+                        }
                     },
                     Some(WsMessage::Binary(_)) => {
                         error!("Received binary data from signal server (expected text). Ignoring.");
                     },
-                    None => {} // Disconnected from signalling server
+                    None => {
+                        debug!("Disconnected from signalling server");
+                        break;
+                    }
                 };
             }
 
             complete => break
         }
     }
+    true
 }
@@ -0,0 +1,328 @@
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc};
+use warp::{
+    http::{header::AUTHORIZATION, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    auth::check_admin,
+    signaling::{matchbox::PeerId, with_state, State},
+};
+
+const ROOM_CLOSED: &str = "the room was closed";
+
+/// A room and its members as returned by the admin api
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct AdminRoomInfo {
+    /// Room path, e.g. `ABCDE` or `next_2`
+    pub room: String,
+    /// Number of a filled next_N room, `None` for id rooms and next_N rooms still filling up
+    pub matched_room: Option<usize>,
+    pub version: Option<String>,
+    /// `None` for next_N rooms still filling up
+    pub host: Option<PeerId>,
+    pub peers: Vec<PeerId>,
+}
+
+/// Details of a connected peer as returned by the admin api
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PeerInfo {
+    pub id: PeerId,
+    pub room: String,
+    pub matched_room: Option<usize>,
+    pub version: Option<String>,
+    /// Player identity from the join token
+    pub player: Option<String>,
+    pub spectator: bool,
+    pub ip: Option<IpAddr>,
+    /// Seconds since the unix epoch
+    pub connected_at: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+}
+
+/// Body of `POST /admin/peers/{id}/kick`
+#[derive(Debug, Deserialize)]
+pub(crate) struct Kick {
+    pub reason: String,
+}
+
+/// Routes under `/admin`, authorized with `Authorization: Bearer <admin_secret>`:
+/// `GET /admin/rooms`, `DELETE /admin/rooms/{code}`, `GET /admin/peers/{id}` and
+/// `POST /admin/peers/{id}/kick`
+pub(crate) fn admin_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let authorization = warp::header::optional::<String>(AUTHORIZATION.as_str());
+
+    let rooms = warp::get()
+        .and(warp::path!("admin" / "rooms"))
+        .and(authorization)
+        .and(with_state(state.clone()))
+        .and_then(rooms_handler);
+
+    let close_room = warp::delete()
+        .and(warp::path!("admin" / "rooms" / String))
+        .and(authorization)
+        .and(with_state(state.clone()))
+        .and_then(close_room_handler);
+
+    let peer = warp::get()
+        .and(warp::path!("admin" / "peers" / PeerId))
+        .and(authorization)
+        .and(with_state(state.clone()))
+        .and_then(peer_handler);
+
+    let kick = warp::post()
+        .and(warp::path!("admin" / "peers" / PeerId / "kick"))
+        .and(authorization)
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(kick_handler);
+
+    rooms.or(close_room).or(peer).or(kick)
+}
+
+async fn rooms_handler(
+    authorization: Option<String>,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let state = state.lock().await;
+    if let Err(status) = check_admin(&state.config.auth, authorization.as_deref()) {
+        return Ok(Box::new(status));
+    }
+    Ok(Box::new(warp::reply::json(&state.admin_rooms())))
+}
+
+async fn close_room_handler(
+    code: String,
+    authorization: Option<String>,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let mut state = state.lock().await;
+    if let Err(status) = check_admin(&state.config.auth, authorization.as_deref()) {
+        return Ok(Box::new(status));
+    }
+    let reply = match state.close_room(&code, ROOM_CLOSED) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    };
+    Ok(Box::new(reply))
+}
+
+async fn peer_handler(
+    peer_id: PeerId,
+    authorization: Option<String>,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let state = state.lock().await;
+    if let Err(status) = check_admin(&state.config.auth, authorization.as_deref()) {
+        return Ok(Box::new(status));
+    }
+    let reply: Box<dyn Reply> = match state.peer_info(&peer_id) {
+        Some(peer) => Box::new(warp::reply::json(&peer)),
+        None => Box::new(StatusCode::NOT_FOUND),
+    };
+    Ok(reply)
+}
+
+async fn kick_handler(
+    peer_id: PeerId,
+    authorization: Option<String>,
+    request: Kick,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let mut state = state.lock().await;
+    if let Err(status) = check_admin(&state.config.auth, authorization.as_deref()) {
+        return Ok(Box::new(status));
+    }
+    let reply = match state.kick(&peer_id, &request.reason) {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    };
+    Ok(Box::new(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::{http::StatusCode, test::WsClient, Filter, Rejection, Reply};
+
+    use crate::{
+        admin::{admin_filter, AdminRoomInfo, PeerInfo},
+        config::{Auth, Config},
+        signaling::{
+            matchbox::{PeerEvent, PeerId},
+            ws_filter, State,
+        },
+    };
+
+    const ADMIN_SECRET: &str = "admin secret";
+
+    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let config = Config {
+            auth: Auth {
+                admin_secret: Some(ADMIN_SECRET.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(State::new(config)));
+        admin_filter(state.clone()).or(ws_filter(state))
+    }
+
+    fn admin_request(method: &str, path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("Bearer {}", ADMIN_SECRET))
+    }
+
+    async fn recv_event(client: &mut WsClient) -> PeerEvent<serde_json::Value> {
+        let message = client.recv().await.unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    async fn connect(
+        api: &(impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static),
+        path: &str,
+    ) -> (WsClient, PeerId) {
+        let mut client = warp::test::ws()
+            .path(path)
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id = match recv_event(&mut client).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected IdAssigned, got {:?}", event),
+        };
+        match recv_event(&mut client).await {
+            PeerEvent::ResumeToken(_) => (client, id),
+            event => panic!("expected ResumeToken, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let api = api();
+        let response = warp::test::request().path("/admin/rooms").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request()
+            .path("/admin/rooms")
+            .header("authorization", "Bearer wrong")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // no admin api without an admin secret
+        let response = admin_request("GET", "/admin/rooms")
+            .reply(&admin_filter(Default::default()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn inspect_and_kick() {
+        let api = api();
+        let (mut client_a, id_a) = connect(&api, "/room_a?protocol=2").await;
+        assert_eq!(
+            recv_event(&mut client_a).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+        let (mut client_b, id_b) = connect(&api, "/room_a?protocol=2").await;
+        assert_eq!(
+            recv_event(&mut client_b).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+        assert_eq!(
+            recv_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+        let (_client_c, id_c) = connect(&api, "/next_2?protocol=2").await;
+
+        let response = admin_request("GET", "/admin/rooms").reply(&api).await;
+        let rooms: Vec<AdminRoomInfo> = serde_json::from_slice(response.body()).unwrap();
+        let mut room_a_peers = vec![id_a.clone(), id_b.clone()];
+        room_a_peers.sort();
+        assert_eq!(
+            rooms,
+            vec![
+                AdminRoomInfo {
+                    room: "next_2".to_string(),
+                    matched_room: None,
+                    version: None,
+                    host: None,
+                    peers: vec![id_c],
+                },
+                AdminRoomInfo {
+                    room: "room_a".to_string(),
+                    matched_room: None,
+                    version: None,
+                    host: Some(id_a.clone()),
+                    peers: room_a_peers,
+                },
+            ]
+        );
+
+        let response = admin_request("GET", &format!("/admin/peers/{}", id_a))
+            .reply(&api)
+            .await;
+        let peer: PeerInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(peer.room, "room_a");
+        // IdAssigned, ResumeToken, HostChanged and NewPeer
+        assert_eq!(peer.messages_sent, 4);
+        assert_eq!(peer.messages_received, 0);
+
+        let response = admin_request("POST", &format!("/admin/peers/{}/kick", id_b))
+            .json(&serde_json::json!({ "reason": "cheating" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            recv_event(&mut client_b).await,
+            PeerEvent::Kicked {
+                reason: "cheating".to_string()
+            }
+        );
+        client_b.recv_closed().await.unwrap();
+        assert_eq!(recv_event(&mut client_a).await, PeerEvent::PeerLeft(id_b));
+
+        let response = admin_request("POST", "/admin/peers/unknown/kick")
+            .json(&serde_json::json!({ "reason": "cheating" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn close_room() {
+        let api = api();
+        let (mut client_a, id_a) = connect(&api, "/room_a?protocol=2").await;
+        assert_eq!(
+            recv_event(&mut client_a).await,
+            PeerEvent::HostChanged(id_a)
+        );
+
+        let response = admin_request("DELETE", "/admin/rooms/room_a")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            recv_event(&mut client_a).await,
+            PeerEvent::Kicked {
+                reason: "the room was closed".to_string()
+            }
+        );
+        client_a.recv_closed().await.unwrap();
+
+        let response = admin_request("DELETE", "/admin/rooms/room_a")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
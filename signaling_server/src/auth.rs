@@ -0,0 +1,326 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::lock::Mutex;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use warp::{
+    http::{header::AUTHORIZATION, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    config::Auth,
+    signaling::{with_state, State},
+};
+
+const DEFAULT_EXPIRES_IN: u64 = 60 * 60;
+
+/// Prefix of a join token passed in `Sec-WebSocket-Protocol`, e.g. `token.<token>`
+const PROTOCOL_PREFIX: &str = "token.";
+
+/// Claims of a join token, allowing `player` to join `room` until `exp`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JoinToken {
+    /// Room path the token is valid for, e.g. `ABCDE` or `next_2`
+    pub room: String,
+    pub player: String,
+    /// Expiry in seconds since the unix epoch
+    pub exp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum TokenError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token expired")]
+    Expired,
+}
+
+fn mac(secret: &str) -> Hmac<Sha256> {
+    Hmac::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length")
+}
+
+/// Encodes and signs a token as `base64url(json).base64url(hmac)`
+pub(crate) fn sign(secret: &str, token: &JoinToken) -> String {
+    let payload =
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(token).expect("error serializing token"));
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks the signature and expiry of a token signed with [`sign`]
+pub(crate) fn verify(secret: &str, token: &str, now: u64) -> Result<JoinToken, TokenError> {
+    let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| TokenError::InvalidSignature)?;
+
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| TokenError::Malformed)?;
+    let token: JoinToken = serde_json::from_slice(&payload).map_err(|_| TokenError::Malformed)?;
+    if token.exp <= now {
+        return Err(TokenError::Expired);
+    }
+    Ok(token)
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// Browsers can't set headers on websockets, so tokens may be sent as one of the
+/// requested subprotocols instead. Returns the protocol entry and the token in it
+pub(crate) fn token_from_protocols(protocols: &str) -> Option<(&str, &str)> {
+    protocols
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| Some((protocol, protocol.strip_prefix(PROTOCOL_PREFIX)?)))
+}
+
+/// Compares in constant time, so the admin secret can't be guessed byte by byte
+fn secrets_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Checks an `Authorization: Bearer <secret>` header against the admin secret.
+/// The admin api does not exist without an admin secret, so that is a 404
+pub(crate) fn check_admin(auth: &Auth, authorization: Option<&str>) -> Result<(), StatusCode> {
+    let admin_secret = auth.admin_secret.as_deref().ok_or(StatusCode::NOT_FOUND)?;
+    let authorized = authorization
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|secret| secrets_equal(secret.trim(), admin_secret));
+    if authorized {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Body of `POST /tokens`
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateToken {
+    pub room: String,
+    pub player: String,
+    /// Seconds until the token expires, an hour by default
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedToken {
+    token: String,
+    expires_at: u64,
+}
+
+/// `POST /tokens`, only available with both a token secret and an admin secret configured
+pub(crate) fn tokens_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("tokens"))
+        .and(warp::header::optional::<String>(AUTHORIZATION.as_str()))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_state(state))
+        .and_then(create_token_handler)
+}
+
+async fn create_token_handler(
+    authorization: Option<String>,
+    request: CreateToken,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let auth = state.lock().await.config.auth.clone();
+    if let Err(status) = check_admin(&auth, authorization.as_deref()) {
+        return Ok(Box::new(status));
+    }
+    let token_secret = match auth.token_secret {
+        Some(token_secret) => token_secret,
+        None => return Ok(Box::new(StatusCode::NOT_FOUND)),
+    };
+
+    let expires_at = unix_now().saturating_add(request.expires_in.unwrap_or(DEFAULT_EXPIRES_IN));
+    let token = JoinToken {
+        room: request.room,
+        player: request.player,
+        exp: expires_at,
+    };
+    Ok(Box::new(warp::reply::json(&CreatedToken {
+        token: sign(&token_secret, &token),
+        expires_at,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::{http::StatusCode, test::WsClient, Filter, Rejection, Reply};
+
+    use crate::{
+        auth::{
+            sign, token_from_protocols, tokens_filter, unix_now, verify, JoinToken, TokenError,
+        },
+        config::{Auth, Config},
+        signaling::{matchbox::PeerEvent, ws_filter, State},
+    };
+
+    const TOKEN_SECRET: &str = "token secret";
+    const ADMIN_SECRET: &str = "admin secret";
+
+    fn token(room: &str, exp: u64) -> JoinToken {
+        JoinToken {
+            room: room.to_string(),
+            player: "player_a".to_string(),
+            exp,
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let claims = token("room_a", 100);
+        let signed = sign(TOKEN_SECRET, &claims);
+        assert_eq!(verify(TOKEN_SECRET, &signed, 99), Ok(claims.clone()));
+        assert_eq!(verify(TOKEN_SECRET, &signed, 100), Err(TokenError::Expired));
+        assert_eq!(
+            verify("other secret", &signed, 0),
+            Err(TokenError::InvalidSignature)
+        );
+
+        // swapping the claims of a valid token
+        let (_, signature) = signed.split_once('.').unwrap();
+        let (payload, _) = sign(TOKEN_SECRET, &token("room_b", 100))
+            .split_once('.')
+            .map(|(payload, signature)| (payload.to_string(), signature.to_string()))
+            .unwrap();
+        assert_eq!(
+            verify(TOKEN_SECRET, &format!("{}.{}", payload, signature), 0),
+            Err(TokenError::InvalidSignature)
+        );
+        assert_eq!(
+            verify(TOKEN_SECRET, "garbage", 0),
+            Err(TokenError::Malformed)
+        );
+    }
+
+    #[test]
+    fn protocol_token() {
+        assert_eq!(
+            token_from_protocols("matchbox, token.abc.def"),
+            Some(("token.abc.def", "abc.def"))
+        );
+        assert_eq!(token_from_protocols("matchbox"), None);
+    }
+
+    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let config = Config {
+            auth: Auth {
+                token_secret: Some(TOKEN_SECRET.to_string()),
+                admin_secret: Some(ADMIN_SECRET.to_string()),
+            },
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(State::new(config)));
+        tokens_filter(state.clone()).or(ws_filter(state))
+    }
+
+    async fn assert_id_assigned(client: &mut WsClient) {
+        let message = client.recv().await.unwrap();
+        let event: PeerEvent<serde_json::Value> =
+            serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert!(matches!(event, PeerEvent::IdAssigned(_)));
+    }
+
+    #[tokio::test]
+    async fn join_tokens() {
+        let api = api();
+        let valid = sign(TOKEN_SECRET, &token("room_a", unix_now() + 60));
+
+        assert!(warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .is_err());
+        assert!(warp::test::ws()
+            .path(&format!("/room_b?token={}&protocol=2", valid))
+            .handshake(api.clone())
+            .await
+            .is_err());
+        let expired = sign(TOKEN_SECRET, &token("room_a", unix_now() - 1));
+        assert!(warp::test::ws()
+            .path(&format!("/room_a?token={}&protocol=2", expired))
+            .handshake(api.clone())
+            .await
+            .is_err());
+
+        let mut client = warp::test::ws()
+            .path(&format!("/room_a?token={}&protocol=2", valid))
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        assert_id_assigned(&mut client).await;
+
+        let mut client = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .header("sec-websocket-protocol", format!("token.{}", valid))
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_id_assigned(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn create_token() {
+        let api = api();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/tokens")
+            .json(&serde_json::json!({ "room": "next_2", "player": "player_a" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/tokens")
+            .header("authorization", format!("Bearer {}", ADMIN_SECRET))
+            .json(&serde_json::json!({ "room": "next_2", "player": "player_a" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let claims = verify(TOKEN_SECRET, body["token"].as_str().unwrap(), unix_now()).unwrap();
+        assert_eq!(claims.room, "next_2");
+        assert_eq!(claims.player, "player_a");
+        assert_eq!(Some(claims.exp), body["expires_at"].as_u64());
+
+        let mut client = warp::test::ws()
+            .path(&format!(
+                "/next_2?token={}&protocol=2",
+                body["token"].as_str().unwrap()
+            ))
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_id_assigned(&mut client).await;
+    }
+}
@@ -0,0 +1,558 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use warp::http::Uri;
+
+/// Command line flags, each of which can also be set with an environment variable.
+/// Flags take precedence over the config file.
+#[derive(Debug, Default, Parser)]
+#[clap(about = "A signalling server for WebRTC peer-to-peer full-mesh networking")]
+pub(crate) struct Args {
+    /// Path to a TOML config file
+    #[clap(long, env = "MATCHBOX_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to bind to, IPv4 or IPv6
+    #[clap(long, env = "MATCHBOX_HOST")]
+    pub host: Option<IpAddr>,
+    #[clap(long, env = "PORT")]
+    pub port: Option<u16>,
+    /// Origins allowed to make cross-origin requests, any origin if none are given
+    #[clap(
+        long = "cors-origin",
+        env = "MATCHBOX_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub cors_origins: Option<Vec<String>>,
+    /// Log filter, e.g. "info" or "signaling_server=debug"
+    #[clap(long, env = "MATCHBOX_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    #[clap(long, env = "MATCHBOX_MAX_ROOMS")]
+    pub max_rooms: Option<usize>,
+    #[clap(long, env = "MATCHBOX_MAX_PEERS_PER_ROOM")]
+    pub max_peers_per_room: Option<usize>,
+    /// e.g. "15s"
+    #[clap(long, env = "MATCHBOX_PING_INTERVAL", value_parser = humantime::parse_duration)]
+    pub ping_interval: Option<Duration>,
+    #[clap(long, env = "MATCHBOX_PONG_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub pong_timeout: Option<Duration>,
+    #[clap(long, env = "MATCHBOX_IDLE_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<Duration>,
+    /// How long to wait for peers to disconnect on SIGTERM before exiting, e.g. "10s"
+    #[clap(long, env = "MATCHBOX_DRAIN_PERIOD", value_parser = humantime::parse_duration)]
+    pub drain_period: Option<Duration>,
+    /// Take client ips from the X-Forwarded-For header, only enable this behind a reverse proxy
+    #[clap(long, env = "MATCHBOX_TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: bool,
+    /// PEM certificate chain, serves https and wss together with --tls-key
+    #[clap(long, env = "MATCHBOX_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[clap(long, env = "MATCHBOX_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Secret for signing join tokens, peers need a valid token to join if this is set
+    #[clap(long, env = "MATCHBOX_TOKEN_SECRET", hide_env_values = true)]
+    pub token_secret: Option<String>,
+    /// Secret for the admin api, which is disabled if this is not set
+    #[clap(long, env = "MATCHBOX_ADMIN_SECRET", hide_env_values = true)]
+    pub admin_secret: Option<String>,
+}
+
+/// Server configuration, see `config.example.toml`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Allowed CORS origins, any origin is allowed if this is empty
+    pub cors_origins: Vec<String>,
+    pub log_level: String,
+    /// Maximum number of id rooms that can exist at the same time
+    pub max_rooms: Option<usize>,
+    /// Maximum number of peers in any room
+    pub max_peers_per_room: Option<usize>,
+    pub rooms: RoomLifetime,
+    pub keepalive: Keepalive,
+    pub rate_limits: RateLimits,
+    pub shutdown: Shutdown,
+    pub matchmaking: Matchmaking,
+    /// How far ahead of time games are scheduled once everyone in a room is ready, so
+    /// the `StartGame` event reaches every peer before it is time to start
+    #[serde(with = "humantime_serde")]
+    pub start_delay: Duration,
+    /// Serve https and wss directly, without a reverse proxy in front
+    pub tls: Option<Tls>,
+    pub auth: Auth,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3536,
+            cors_origins: vec![],
+            log_level: "info".to_string(),
+            max_rooms: None,
+            max_peers_per_room: None,
+            rooms: Default::default(),
+            keepalive: Default::default(),
+            rate_limits: Default::default(),
+            shutdown: Default::default(),
+            matchmaking: Default::default(),
+            start_delay: Duration::from_secs(1),
+            tls: None,
+            auth: Default::default(),
+        }
+    }
+}
+
+/// How long id rooms are kept around
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RoomLifetime {
+    /// How long a room may stay empty before it is destroyed
+    #[serde(with = "humantime_serde")]
+    pub empty_ttl: Duration,
+    /// Rooms are destroyed this long after creation, even if peers are still in them
+    #[serde(with = "humantime_serde")]
+    pub max_lifetime: Option<Duration>,
+    /// How often to look for expired rooms
+    #[serde(with = "humantime_serde")]
+    pub reap_interval: Duration,
+}
+
+impl Default for RoomLifetime {
+    fn default() -> Self {
+        RoomLifetime {
+            empty_ttl: Duration::from_secs(5 * 60),
+            max_lifetime: None,
+            reap_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Timings for detecting dead connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Keepalive {
+    /// How often to ping peers
+    #[serde(with = "humantime_serde")]
+    pub ping_interval: Duration,
+    /// How long a peer has to answer a ping
+    #[serde(with = "humantime_serde")]
+    pub pong_timeout: Duration,
+    /// Legacy clients that don't send their `Uuid` within this long are disconnected.
+    /// Everyone else stays connected as long as they answer pings
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// How long peers whose connection was lost keep their id and place in the room,
+    /// to resume their session. Zero disables resuming
+    #[serde(with = "humantime_serde")]
+    pub resume_grace: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+            resume_grace: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Limits on how much a single client may send
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimits {
+    /// Sustained number of messages per second a single connection may send
+    pub messages_per_second: f64,
+    /// Number of messages a single connection may send in a burst
+    pub burst: u32,
+    /// Sustained number of messages per second all connections from one ip may send
+    pub ip_messages_per_second: f64,
+    /// Number of messages all connections from one ip may send in a burst
+    pub ip_burst: u32,
+    /// Maximum number of concurrent websockets from one ip
+    pub max_connections_per_ip: usize,
+    /// Maximum size of a single incoming message in bytes
+    pub max_message_size: usize,
+    /// Maximum size of the data of a single broadcast or room state value in bytes
+    pub max_broadcast_size: usize,
+    /// Maximum number of keys in the room-wide state and in each peer's namespace
+    pub max_state_keys: usize,
+    /// Sustained number of rooms one ip may create per minute with `POST /rooms`
+    pub rooms_per_minute: f64,
+    /// Number of rooms one ip may create in a burst
+    pub room_burst: u32,
+    /// Use the last ip in the X-Forwarded-For header as the client ip for the per-ip limits.
+    /// Only enable this behind a reverse proxy that sets the header, e.g. on Heroku
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            messages_per_second: 20.,
+            burst: 100,
+            ip_messages_per_second: 100.,
+            ip_burst: 500,
+            max_connections_per_ip: 16,
+            max_message_size: 64 * 1024,
+            max_broadcast_size: 1024,
+            max_state_keys: 64,
+            rooms_per_minute: 10.,
+            room_burst: 5,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+/// What happens on SIGTERM or SIGINT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Shutdown {
+    /// How long to wait for in-flight handshakes and for peers to disconnect before exiting
+    #[serde(with = "humantime_serde")]
+    pub drain_period: Duration,
+    /// How long clients are told to wait before reconnecting, e.g. until a restarted
+    /// server is up again
+    #[serde(with = "humantime_serde")]
+    pub reconnect_after: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            drain_period: Duration::from_secs(10),
+            reconnect_after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Rated next_N rooms, joined with `?rating=`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Matchmaking {
+    /// How far apart in rating players may be when they start looking for a match
+    pub initial_window: u32,
+    /// How much the acceptable rating difference grows each second a player waits
+    pub window_growth: f64,
+    /// Players that found no match in this time are told so and disconnected
+    #[serde(with = "humantime_serde")]
+    pub max_wait: Duration,
+    /// How often to look for matches again, as windows widen
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for Matchmaking {
+    fn default() -> Self {
+        Matchmaking {
+            initial_window: 100,
+            window_growth: 25.,
+            max_wait: Duration::from_secs(60),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Certificate and key for serving TLS, both are reloaded on SIGHUP
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Tls {
+    /// PEM file with the certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+    /// Connections that don't finish the TLS handshake in time are dropped
+    #[serde(with = "humantime_serde", default = "default_handshake_timeout")]
+    pub handshake_timeout: Duration,
+}
+
+fn default_handshake_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Secrets for join tokens and the admin api
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Auth {
+    /// Secret for signing join tokens. If set, peers need a valid token to join a room
+    pub token_secret: Option<String>,
+    /// Secret for the admin api, sent as `Authorization: Bearer <secret>`.
+    /// The admin api is disabled if this is not set
+    pub admin_secret: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ConfigError {
+    #[error("could not read config file {0:?}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("could not parse config file {0:?}: {1}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+impl Config {
+    /// Loads the config file given in `args`, if any, applies the flags on top and validates
+    /// the result
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    fn apply_args(&mut self, args: Args) {
+        if let Some(host) = args.host {
+            self.host = host;
+        }
+        if let Some(port) = args.port {
+            self.port = port;
+        }
+        if let Some(cors_origins) = args.cors_origins {
+            self.cors_origins = cors_origins;
+        }
+        if let Some(log_level) = args.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(max_rooms) = args.max_rooms {
+            self.max_rooms = Some(max_rooms);
+        }
+        if let Some(max_peers_per_room) = args.max_peers_per_room {
+            self.max_peers_per_room = Some(max_peers_per_room);
+        }
+        if let Some(ping_interval) = args.ping_interval {
+            self.keepalive.ping_interval = ping_interval;
+        }
+        if let Some(pong_timeout) = args.pong_timeout {
+            self.keepalive.pong_timeout = pong_timeout;
+        }
+        if let Some(idle_timeout) = args.idle_timeout {
+            self.keepalive.idle_timeout = idle_timeout;
+        }
+        if let Some(drain_period) = args.drain_period {
+            self.shutdown.drain_period = drain_period;
+        }
+        if args.trust_forwarded_for {
+            self.rate_limits.trust_forwarded_for = true;
+        }
+        if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+            let handshake_timeout = self
+                .tls
+                .take()
+                .map_or_else(default_handshake_timeout, |tls| tls.handshake_timeout);
+            self.tls = Some(Tls {
+                cert,
+                key,
+                handshake_timeout,
+            });
+        }
+        if let Some(token_secret) = args.token_secret {
+            self.auth.token_secret = Some(token_secret);
+        }
+        if let Some(admin_secret) = args.admin_secret {
+            self.auth.admin_secret = Some(admin_secret);
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        for origin in &self.cors_origins {
+            let valid = origin
+                .parse::<Uri>()
+                .map(|uri| {
+                    uri.scheme().is_some()
+                        && uri.authority().is_some()
+                        && matches!(uri.path(), "" | "/")
+                        && uri.query().is_none()
+                })
+                .unwrap_or(false);
+            if !valid {
+                return invalid(format!(
+                    "cors origin {:?} is not of the form scheme://host[:port]",
+                    origin
+                ));
+            }
+        }
+        for directive in self.log_level.split(',') {
+            let level = directive.rsplit('=').next().unwrap_or_default();
+            if directive.contains('=') && level.parse::<log::LevelFilter>().is_err() {
+                return invalid(format!(
+                    "log level {:?} in {:?} is not one of off, error, warn, info, debug or trace",
+                    level, self.log_level
+                ));
+            }
+        }
+        if self.max_rooms == Some(0) {
+            return invalid("max_rooms must be at least 1".to_string());
+        }
+        if self.max_peers_per_room == Some(0) {
+            return invalid("max_peers_per_room must be at least 1".to_string());
+        }
+        if self.rooms.reap_interval.is_zero() {
+            return invalid("rooms.reap_interval must be greater than zero".to_string());
+        }
+        let keepalive = &self.keepalive;
+        if keepalive.ping_interval.is_zero()
+            || keepalive.pong_timeout.is_zero()
+            || keepalive.idle_timeout.is_zero()
+        {
+            return invalid(
+                "keepalive.ping_interval, keepalive.pong_timeout and keepalive.idle_timeout \
+                 must be greater than zero"
+                    .to_string(),
+            );
+        }
+        if self
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.handshake_timeout.is_zero())
+        {
+            return invalid("tls.handshake_timeout must be greater than zero".to_string());
+        }
+        let limits = &self.rate_limits;
+        if !(limits.messages_per_second > 0. && limits.ip_messages_per_second > 0.) {
+            return invalid(
+                "rate_limits.messages_per_second and rate_limits.ip_messages_per_second \
+                 must be greater than zero"
+                    .to_string(),
+            );
+        }
+        if limits.burst == 0 || limits.ip_burst == 0 {
+            return invalid(
+                "rate_limits.burst and rate_limits.ip_burst must be at least 1".to_string(),
+            );
+        }
+        if limits.max_connections_per_ip == 0 {
+            return invalid("rate_limits.max_connections_per_ip must be at least 1".to_string());
+        }
+        if limits.max_message_size == 0 || limits.max_broadcast_size == 0 {
+            return invalid(
+                "rate_limits.max_message_size and rate_limits.max_broadcast_size \
+                 must be at least 1"
+                    .to_string(),
+            );
+        }
+        if limits.max_state_keys == 0 {
+            return invalid("rate_limits.max_state_keys must be at least 1".to_string());
+        }
+        let matchmaking = &self.matchmaking;
+        if matchmaking.window_growth.is_nan() || matchmaking.window_growth < 0. {
+            return invalid("matchmaking.window_growth can't be negative".to_string());
+        }
+        if matchmaking.interval.is_zero() {
+            return invalid("matchmaking.interval must be greater than zero".to_string());
+        }
+        let secrets = [&self.auth.token_secret, &self.auth.admin_secret];
+        if secrets.iter().any(|secret| secret.as_deref() == Some("")) {
+            return invalid("auth.token_secret and auth.admin_secret can't be empty".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use std::{net::IpAddr, time::Duration};
+
+    use crate::config::{Args, Config};
+
+    #[test]
+    fn parse_file() {
+        let config: Config = toml::from_str(
+            r#"
+            host = "::"
+            port = 8080
+            cors_origins = ["https://example.com"]
+            max_peers_per_room = 4
+
+            [keepalive]
+            ping_interval = "5s"
+
+            [rooms]
+            max_lifetime = "2h"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.host, "::".parse::<IpAddr>().unwrap());
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.max_peers_per_room, Some(4));
+        assert_eq!(config.keepalive.ping_interval, Duration::from_secs(5));
+        assert_eq!(
+            config.keepalive.pong_timeout,
+            Config::default().keepalive.pong_timeout
+        );
+        assert_eq!(
+            config.rooms.max_lifetime,
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn example_config_has_defaults() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn args_override_file() {
+        let mut config: Config = toml::from_str("port = 8080\nlog_level = \"warn\"").unwrap();
+        config.apply_args(Args::parse_from([
+            "signaling_server",
+            "--port",
+            "9000",
+            "--idle-timeout",
+            "2m",
+        ]));
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.log_level, "warn");
+        assert_eq!(config.keepalive.idle_timeout, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(toml::from_str::<Config>("prot = 8080").is_err());
+
+        let config = Config {
+            cors_origins: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            log_level: "signaling_server=loud".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.keepalive.idle_timeout = Duration::ZERO;
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{de::DeserializeOwned, Serialize};
+use warp::ws::Message;
+
+/// Websocket subprotocol clients request to talk MessagePack instead of JSON
+pub(crate) const MSGPACK_PROTOCOL: &str = "msgpack";
+
+/// How a connection encodes requests and events. JSON is sent in text frames,
+/// MessagePack in binary frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DecodeError {
+    #[error("Frame type doesn't match the encoding")]
+    FrameType,
+    #[error("Json error")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack error")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+}
+
+impl Encoding {
+    /// Picks the encoding from the subprotocols a client requested
+    pub fn from_protocols(protocols: &str) -> Self {
+        if protocols
+            .split(',')
+            .map(str::trim)
+            .any(|protocol| protocol == MSGPACK_PROTOCOL)
+        {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, message: &Message) -> Result<T, DecodeError> {
+        match self {
+            Encoding::Json => {
+                let text = message.to_str().map_err(|_| DecodeError::FrameType)?;
+                Ok(serde_json::from_str(text)?)
+            }
+            Encoding::MessagePack if message.is_binary() => {
+                Ok(rmp_serde::from_slice(message.as_bytes())?)
+            }
+            Encoding::MessagePack => Err(DecodeError::FrameType),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Message {
+        match self {
+            Encoding::Json => {
+                Message::text(serde_json::to_string(value).expect("error serializing message"))
+            }
+            Encoding::MessagePack => {
+                Message::binary(rmp_serde::to_vec_named(value).expect("error serializing message"))
+            }
+        }
+    }
+}
+
+/// A value going to several connections, serialized at most once per encoding
+pub(crate) struct Encoded<'a, T> {
+    value: &'a T,
+    json: Option<Message>,
+    message_pack: Option<Message>,
+}
+
+impl<'a, T: Serialize> Encoded<'a, T> {
+    pub fn new(value: &'a T) -> Self {
+        Encoded {
+            value,
+            json: None,
+            message_pack: None,
+        }
+    }
+
+    pub fn message(&mut self, encoding: Encoding) -> Message {
+        let message = match encoding {
+            Encoding::Json => &mut self.json,
+            Encoding::MessagePack => &mut self.message_pack,
+        };
+        let value = self.value;
+        message
+            .get_or_insert_with(|| encoding.encode(value))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::ws::Message;
+
+    use crate::encoding::{Encoded, Encoding};
+    use crate::signaling::matchbox;
+
+    type PeerRequest = matchbox::PeerRequest<serde_json::Value>;
+    type PeerEvent = matchbox::PeerEvent<serde_json::Value>;
+
+    #[test]
+    fn from_protocols() {
+        assert_eq!(Encoding::from_protocols("msgpack"), Encoding::MessagePack);
+        assert_eq!(
+            Encoding::from_protocols("token.abc, msgpack"),
+            Encoding::MessagePack
+        );
+        assert_eq!(Encoding::from_protocols("token.abc"), Encoding::Json);
+    }
+
+    #[test]
+    fn encode_events() {
+        let event = PeerEvent::Signal {
+            sender: "a".to_string(),
+            data: serde_json::json!({ "Offer": "sdp" }),
+        };
+        let mut encoded = Encoded::new(&event);
+
+        let json = encoded.message(Encoding::Json);
+        assert_eq!(json, Message::text(serde_json::to_string(&event).unwrap()));
+        let binary = encoded.message(Encoding::MessagePack);
+        assert!(binary.is_binary());
+        assert_eq!(
+            rmp_serde::from_slice::<PeerEvent>(binary.as_bytes()).unwrap(),
+            event
+        );
+        assert_eq!(encoded.message(Encoding::Json), json);
+    }
+
+    #[test]
+    fn decode_requests() {
+        let request = PeerRequest::Ready(true);
+        let binary = Message::binary(rmp_serde::to_vec_named(&request).unwrap());
+        assert_eq!(
+            Encoding::MessagePack
+                .decode::<PeerRequest>(&binary)
+                .unwrap(),
+            request
+        );
+        assert!(Encoding::Json.decode::<PeerRequest>(&binary).is_err());
+
+        let text = Message::text(serde_json::to_string(&request).unwrap());
+        assert!(Encoding::MessagePack.decode::<PeerRequest>(&text).is_err());
+    }
+}
@@ -1,20 +1,40 @@
-use log::info;
-use std::env;
+use clap::Parser;
+use log::{error, info, warn};
 use warp::{http::StatusCode, hyper::Method, Filter, Rejection, Reply};
 
+use futures::{lock::Mutex, FutureExt};
 pub use signaling::matchbox::PeerId;
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpListener, select, time};
 
+mod admin;
+mod auth;
+mod config;
+mod encoding;
+mod matchmaking;
+mod metrics;
+mod rate_limit;
+mod room_state;
+mod rooms;
 mod signaling;
+mod tls;
 
 #[tokio::main]
 async fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        env::set_var("RUST_LOG", "matchbox_server=info");
+    let config = match config::Config::load(config::Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.parse_filters(&config.log_level);
+    // RUST_LOG still works as usual, on top of the configured level
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
     }
-    let port = env::var("PORT").ok().unwrap_or("3536".to_owned());
-    let host: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0,0,0,0), port.parse().unwrap()));
-    pretty_env_logger::init();
+    logger.init();
 
     let health_route = warp::path("health").and_then(health_handler);
 
@@ -27,8 +47,12 @@ async fn main() {
     //     .allow_any_origin()
     //     .build();
 
-    let cors = warp::cors()
-        .allow_any_origin()
+    let cors = if config.cors_origins.is_empty() {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(config.cors_origins.iter().map(String::as_str))
+    };
+    let cors = cors
         .allow_headers(vec![
             "Access-Control-Allow-Headers",
             "Access-Control-Request-Method",
@@ -52,13 +76,128 @@ async fn main() {
     //     .allow_any_origin()
     //     .allow_methods(&[Method::GET]);
 
+    let addr = config.addr();
+    let reap_interval = config.rooms.reap_interval;
+    let matchmaking_interval = config.matchmaking.interval;
+    let drain_period = config.shutdown.drain_period;
+    let tls = config.tls.clone();
+    let state = Arc::new(Mutex::new(signaling::State::new(config)));
+    signaling::spawn_room_reaper(state.clone(), reap_interval);
+    signaling::spawn_matchmaker(state.clone(), matchmaking_interval);
+
     let routes = health_route
-        .or(signaling::ws_filter(Default::default()))
+        .or(rooms::rooms_filter(state.clone()))
+        .or(metrics::metrics_filter(state.clone()))
+        .or(auth::tokens_filter(state.clone()))
+        .or(admin::admin_filter(state.clone()))
+        .or(signaling::ws_filter(state.clone()))
         .with(cors)
         .with(log);
 
-    info!("Starting matchbox signaling server");
-    warp::serve(routes).run(host).await;
+    let shutdown = {
+        let state = state.clone();
+        async move {
+            shutdown_signal().await;
+            info!(
+                "Shutting down, waiting up to {:?} for peers to leave",
+                drain_period
+            );
+            state.lock().await.shutdown();
+        }
+        .boxed()
+        .shared()
+    };
+
+    let server = async {
+        match tls {
+            Some(tls) => {
+                let certificates = match tls::Certificates::load(tls) {
+                    Ok(certificates) => certificates,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                };
+                #[cfg(unix)]
+                if let Err(e) = tls::spawn_reload_on_sighup(certificates.clone()) {
+                    warn!("Can't reload the TLS certificate on SIGHUP: {}", e);
+                }
+                let listener = match TcpListener::bind(addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("Failed to bind {}: {}", addr, e);
+                        std::process::exit(1);
+                    }
+                };
+                info!("Starting matchbox signaling server on {} with TLS", addr);
+                if let Err(e) = tls::serve(routes, listener, certificates, shutdown.clone()).await {
+                    error!("Server error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            None => {
+                let server =
+                    warp::serve(routes).try_bind_with_graceful_shutdown(addr, shutdown.clone());
+                let (_, server) = match server {
+                    Ok(server) => server,
+                    Err(e) => {
+                        error!("Failed to bind {}: {}", addr, e);
+                        std::process::exit(1);
+                    }
+                };
+                info!("Starting matchbox signaling server on {}", addr);
+                server.await;
+            }
+        }
+        // The server stops once in-flight handshakes are done, websockets live on until
+        // the peers close them
+        signaling::all_peers_left(state.clone()).await;
+    };
+    let drain = async {
+        shutdown.clone().await;
+        time::sleep(drain_period).await;
+    };
+
+    select! {
+        _ = server => info!("All peers left"),
+        _ = drain => {
+            warn!("Drain period is over, closing the remaining connections");
+            state.lock().await.close_connections();
+            // give the close frames a moment to go out
+            let _ = time::timeout(Duration::from_secs(1), signaling::all_peers_left(state)).await;
+        }
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Can't listen for SIGTERM: {}", e);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Can't listen for SIGINT: {}", e);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
 pub async fn health_handler() -> std::result::Result<impl Reply, Rejection> {
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use crate::{config::Matchmaking, signaling::matchbox::PeerId};
+
+/// A peer waiting in a rated queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Candidate {
+    pub id: PeerId,
+    pub rating: u32,
+    pub waited: Duration,
+}
+
+impl Matchmaking {
+    /// How far apart in rating the opponents of a peer that waited this long may be
+    pub fn window(&self, waited: Duration) -> f64 {
+        f64::from(self.initial_window) + self.window_growth * waited.as_secs_f64()
+    }
+}
+
+/// Groups candidates into matches of `size` players, each with a rating spread that every
+/// member of the match accepts. The tightest matches are formed first
+pub(crate) fn find_matches(
+    mut candidates: Vec<Candidate>,
+    size: usize,
+    config: &Matchmaking,
+) -> Vec<Vec<Candidate>> {
+    candidates.sort_by_key(|candidate| candidate.rating);
+    let mut matches = vec![];
+    loop {
+        // in rating order, the players of the tightest match are next to each other
+        let best = candidates
+            .windows(size)
+            .enumerate()
+            .filter_map(|(start, group)| {
+                let spread = f64::from(group[size - 1].rating - group[0].rating);
+                let accepted = group
+                    .iter()
+                    .all(|candidate| spread <= config.window(candidate.waited));
+                accepted.then_some((spread, start))
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        match best {
+            Some((_, start)) => matches.push(candidates.drain(start..start + size).collect()),
+            None => return matches,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        config::Matchmaking,
+        matchmaking::{find_matches, Candidate},
+    };
+
+    fn candidate(id: &str, rating: u32, waited_secs: u64) -> Candidate {
+        Candidate {
+            id: id.to_string(),
+            rating,
+            waited: Duration::from_secs(waited_secs),
+        }
+    }
+
+    fn ids(matches: Vec<Vec<Candidate>>) -> Vec<Vec<String>> {
+        matches
+            .into_iter()
+            .map(|group| group.into_iter().map(|candidate| candidate.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn similar_ratings() {
+        let config = Matchmaking {
+            initial_window: 100,
+            window_growth: 10.,
+            ..Default::default()
+        };
+        let candidates = vec![
+            candidate("a", 1000, 0),
+            candidate("b", 1500, 0),
+            candidate("c", 1050, 0),
+            candidate("d", 1480, 0),
+            candidate("e", 2000, 0),
+        ];
+        assert_eq!(
+            ids(find_matches(candidates, 2, &config)),
+            vec![vec!["d", "b"], vec!["a", "c"]]
+        );
+    }
+
+    #[test]
+    fn widening_window() {
+        let config = Matchmaking {
+            initial_window: 100,
+            window_growth: 10.,
+            ..Default::default()
+        };
+        let a = candidate("a", 1000, 0);
+        let b = candidate("b", 1200, 0);
+        assert!(find_matches(vec![a.clone(), b.clone()], 2, &config).is_empty());
+
+        // both have to accept the match
+        let a = candidate("a", 1000, 10);
+        assert!(find_matches(vec![a.clone(), b.clone()], 2, &config).is_empty());
+        let b = candidate("b", 1200, 10);
+        assert_eq!(
+            ids(find_matches(vec![a, b], 2, &config)),
+            vec![vec!["a", "b"]]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use futures::lock::Mutex;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::Arc;
+use warp::{http::header::CONTENT_TYPE, Filter, Rejection, Reply};
+
+use crate::signaling::{with_state, State};
+
+/// Prometheus metrics of a signaling server
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub connected_peers: IntGauge,
+    pub id_rooms: IntGauge,
+    /// Peers waiting in next_N queues, rated or not, by number of players and version
+    pub waiting_peers: IntGaugeVec,
+    pub signals_relayed: IntCounter,
+    /// Requests that could not be parsed, by `RequestError` variant
+    pub parse_errors: IntCounterVec,
+    /// Time from joining a next_N queue until the room is complete
+    pub matchmaking_wait_seconds: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("matchbox".to_string()), None)?;
+
+        let connected_peers = IntGauge::new("connected_peers", "Number of connected peers")?;
+        let id_rooms = IntGauge::new("id_rooms", "Number of active id rooms")?;
+        let waiting_peers = IntGaugeVec::new(
+            Opts::new("waiting_peers", "Number of peers waiting in next_N queues"),
+            &["players", "version"],
+        )?;
+        let signals_relayed =
+            IntCounter::new("signals_relayed_total", "Number of signals relayed")?;
+        let parse_errors = IntCounterVec::new(
+            Opts::new(
+                "parse_errors_total",
+                "Number of requests that could not be parsed",
+            ),
+            &["error"],
+        )?;
+        let matchmaking_wait_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "matchmaking_wait_seconds",
+                "Time peers waited in next_N queues until their room was complete",
+            )
+            .buckets(exponential_buckets(0.5, 2.0, 12)?),
+            &["players"],
+        )?;
+
+        registry.register(Box::new(connected_peers.clone()))?;
+        registry.register(Box::new(id_rooms.clone()))?;
+        registry.register(Box::new(waiting_peers.clone()))?;
+        registry.register(Box::new(signals_relayed.clone()))?;
+        registry.register(Box::new(parse_errors.clone()))?;
+        registry.register(Box::new(matchmaking_wait_seconds.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            connected_peers,
+            id_rooms,
+            waiting_peers,
+            signals_relayed,
+            parse_errors,
+            matchmaking_wait_seconds,
+        })
+    }
+
+    /// Encodes all metrics in the prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("error encoding metrics");
+        String::from_utf8(buffer).expect("metrics are not valid utf-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new().expect("error registering metrics")
+    }
+}
+
+/// `GET /metrics`
+pub(crate) fn metrics_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("metrics"))
+        .and(with_state(state))
+        .and_then(metrics_handler)
+}
+
+async fn metrics_handler(state: Arc<Mutex<State>>) -> std::result::Result<impl Reply, Rejection> {
+    let metrics = state.lock().await.metrics.encode();
+    Ok(warp::reply::with_header(
+        metrics,
+        CONTENT_TYPE,
+        TextEncoder::new().format_type(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::ws::Message;
+
+    use crate::{metrics::metrics_filter, signaling::ws_filter};
+
+    #[tokio::test]
+    async fn metrics() {
+        let state = Arc::new(Mutex::new(Default::default()));
+        let api = ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_a.recv().await.unwrap();
+
+        let mut client_b = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_b.recv().await.unwrap();
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_c.recv().await.unwrap();
+        // ResumeToken, HostChanged
+        client_c.recv().await.unwrap();
+        client_c.recv().await.unwrap();
+        client_c.send(Message::text("{")).await;
+        client_c.recv().await.unwrap();
+
+        let mut client_d = warp::test::ws()
+            .path("/next_4?version=1.2&rating=1500&protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_d.recv().await.unwrap();
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&metrics_filter(state))
+            .await;
+        let body = std::str::from_utf8(response.body()).unwrap();
+
+        assert!(body.contains("matchbox_connected_peers 4"));
+        assert!(body.contains("matchbox_id_rooms 1"));
+        // the next_2 queue is gone once the room is filled
+        assert!(!body.contains(r#"matchbox_waiting_peers{players="2""#));
+        assert!(body.contains(r#"matchbox_waiting_peers{players="4",version="1.2"} 1"#));
+        assert!(body.contains(r#"matchbox_parse_errors_total{error="JsonError"} 1"#));
+        assert!(body.contains(r#"matchbox_matchmaking_wait_seconds_count{players="2"} 2"#));
+    }
+}
@@ -0,0 +1,149 @@
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::Instant;
+use warp::{Filter, Rejection};
+
+use crate::tls::RemoteAddr;
+
+/// Outcome of taking a token, see [`TokenBucket::take`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Limit {
+    Allowed,
+    /// The message should be dropped
+    Throttled,
+    /// The client keeps going over the limit and should be disconnected
+    Exceeded,
+}
+
+/// Token bucket that can go into debt by up to one full burst.
+///
+/// Messages taken while the bucket is empty are throttled, but still add to the debt, so
+/// clients that keep sending faster than allowed end up exceeding the limit.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = (now - self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Whether the bucket refilled completely, so it can be forgotten
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    pub fn take(&mut self) -> Limit {
+        self.refill();
+
+        self.tokens -= 1.;
+        if self.tokens >= 0. {
+            Limit::Allowed
+        } else if self.tokens > -self.burst {
+            Limit::Throttled
+        } else {
+            Limit::Exceeded
+        }
+    }
+}
+
+/// Connections and messages of all peers connected from the same ip
+#[derive(Debug)]
+pub(crate) struct IpState {
+    pub connections: usize,
+    pub bucket: TokenBucket,
+}
+
+/// Where a websocket connection comes from
+#[derive(Debug, Clone)]
+pub(crate) struct ClientAddr {
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<String>,
+}
+
+impl ClientAddr {
+    /// Ip of the client. Behind a reverse proxy, like on Heroku, the remote address is the
+    /// proxy's, so the last ip in `X-Forwarded-For` (the one the proxy saw) may be trusted instead
+    pub fn ip(&self, trust_forwarded_for: bool) -> Option<IpAddr> {
+        let forwarded = self
+            .forwarded_for
+            .as_ref()
+            .filter(|_| trust_forwarded_for)
+            .and_then(|forwarded_for| forwarded_for.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or_else(|| self.remote.map(|remote| remote.ip()))
+    }
+}
+
+pub(crate) fn client_addr() -> impl Filter<Extract = (ClientAddr,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |remote: Option<SocketAddr>, tls_remote: Option<RemoteAddr>, forwarded_for| {
+                ClientAddr {
+                    remote: remote.or(tls_remote.map(|RemoteAddr(remote)| remote)),
+                    forwarded_for,
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time;
+
+    use crate::rate_limit::{ClientAddr, Limit, TokenBucket};
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket() {
+        let mut bucket = TokenBucket::new(2., 2);
+
+        assert_eq!(bucket.take(), Limit::Allowed);
+        assert_eq!(bucket.take(), Limit::Allowed);
+        assert_eq!(bucket.take(), Limit::Throttled);
+        // throttled messages count as well
+        time::advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.take(), Limit::Throttled);
+        assert_eq!(bucket.take(), Limit::Exceeded);
+
+        // refills over time, but never above the burst
+        time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.take(), Limit::Allowed);
+        assert_eq!(bucket.take(), Limit::Allowed);
+        assert_eq!(bucket.take(), Limit::Throttled);
+    }
+
+    #[test]
+    fn client_ip() {
+        let client = ClientAddr {
+            remote: Some("10.0.0.1:1234".parse().unwrap()),
+            forwarded_for: Some("1.2.3.4, 5.6.7.8".to_string()),
+        };
+        assert_eq!(client.ip(false), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(client.ip(true), Some("5.6.7.8".parse().unwrap()));
+
+        let client = ClientAddr {
+            remote: Some("10.0.0.1:1234".parse().unwrap()),
+            forwarded_for: None,
+        };
+        assert_eq!(client.ip(true), Some("10.0.0.1".parse().unwrap()));
+    }
+}
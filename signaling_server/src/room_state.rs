@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use crate::signaling::matchbox::{PeerId, StateChange};
+
+type StateChanges = Vec<StateChange<serde_json::Value>>;
+
+/// Key-value state of a room, kept in sync with every peer in it. Each peer has a
+/// namespace of its own, room-wide keys can only be set by the host
+#[derive(Debug, Default)]
+pub(crate) struct RoomState {
+    room: BTreeMap<String, serde_json::Value>,
+    peers: BTreeMap<PeerId, BTreeMap<String, serde_json::Value>>,
+}
+
+impl RoomState {
+    fn keys_mut(&mut self, peer: Option<&PeerId>) -> &mut BTreeMap<String, serde_json::Value> {
+        match peer {
+            Some(peer) => self.peers.entry(peer.clone()).or_default(),
+            None => &mut self.room,
+        }
+    }
+
+    /// Applies a change, unless it adds a key to a namespace that has `max_keys` already.
+    /// Returns whether the change could be applied
+    pub fn apply(&mut self, change: &StateChange<serde_json::Value>, max_keys: usize) -> bool {
+        let keys = self.keys_mut(change.peer.as_ref());
+        match &change.value {
+            Some(value) => {
+                if keys.len() >= max_keys && !keys.contains_key(&change.key) {
+                    return false;
+                }
+                keys.insert(change.key.clone(), value.clone());
+            }
+            None => {
+                keys.remove(&change.key);
+            }
+        }
+        if let Some(peer) = &change.peer {
+            if self.peers.get(peer).is_some_and(BTreeMap::is_empty) {
+                self.peers.remove(peer);
+            }
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.room.is_empty() && self.peers.is_empty()
+    }
+
+    /// Every key as a change, for peers joining the room
+    pub fn snapshot(&self) -> StateChanges {
+        let room = self.room.iter().map(|(key, value)| (None, key, value));
+        let peers = self.peers.iter().flat_map(|(peer, keys)| {
+            keys.iter()
+                .map(move |(key, value)| (Some(peer.clone()), key, value))
+        });
+        room.chain(peers)
+            .map(|(peer, key, value)| StateChange {
+                peer,
+                key: key.clone(),
+                value: Some(value.clone()),
+            })
+            .collect()
+    }
+
+    /// Forgets the namespace of a peer that left. Returns the removed keys as changes
+    pub fn remove_peer(&mut self, peer: &PeerId) -> StateChanges {
+        let keys = self.peers.remove(peer).unwrap_or_default();
+        keys.into_keys()
+            .map(|key| StateChange {
+                peer: Some(peer.clone()),
+                key,
+                value: None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{room_state::RoomState, signaling::matchbox::StateChange};
+
+    fn change(
+        peer: Option<&str>,
+        key: &str,
+        value: Option<serde_json::Value>,
+    ) -> StateChange<serde_json::Value> {
+        StateChange {
+            peer: peer.map(str::to_string),
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn apply_and_snapshot() {
+        let mut state = RoomState::default();
+        assert!(state.apply(&change(None, "map", Some(json!("forest"))), 2));
+        assert!(state.apply(&change(Some("a"), "color", Some(json!("red"))), 2));
+        assert!(state.apply(&change(Some("a"), "name", Some(json!("alice"))), 2));
+        // overwriting is fine, adding a third key is not
+        assert!(state.apply(&change(Some("a"), "color", Some(json!("blue"))), 2));
+        assert!(!state.apply(&change(Some("a"), "ready", Some(json!(true))), 2));
+        assert!(state.apply(&change(Some("b"), "color", Some(json!("red"))), 2));
+
+        assert_eq!(
+            state.snapshot(),
+            vec![
+                change(None, "map", Some(json!("forest"))),
+                change(Some("a"), "color", Some(json!("blue"))),
+                change(Some("a"), "name", Some(json!("alice"))),
+                change(Some("b"), "color", Some(json!("red"))),
+            ]
+        );
+
+        assert_eq!(
+            state.remove_peer(&"a".to_string()),
+            vec![
+                change(Some("a"), "color", None),
+                change(Some("a"), "name", None)
+            ]
+        );
+        assert!(state.apply(&change(Some("b"), "color", None), 2));
+        assert!(state.apply(&change(None, "map", None), 2));
+        assert!(state.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use futures::lock::Mutex;
+use log::warn;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{num::NonZeroUsize, sync::Arc};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::{
+    rate_limit::{client_addr, ClientAddr, Limit},
+    signaling::{valid_version, with_state, State},
+};
+
+const CODE_CHARS: &[u8] = b"ABCDEFGHKLMNOPRSTUVWXYZ";
+const CODE_LENGTH: usize = 5;
+
+/// Room details as returned by the http api
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RoomInfo {
+    pub code: String,
+    pub occupancy: usize,
+    pub capacity: Option<usize>,
+    pub version: Option<String>,
+    pub public: bool,
+}
+
+/// Body of `POST /rooms`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct CreateRoom {
+    pub max: Option<NonZeroUsize>,
+    pub version: Option<String>,
+    pub public: bool,
+}
+
+pub(crate) fn create_code() -> String {
+    let mut rng = thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| {
+            let idx = rng.gen_range(0..CODE_CHARS.len());
+            CODE_CHARS[idx] as char
+        })
+        .collect()
+}
+
+/// `POST /rooms`, `GET /rooms` and `GET /rooms/{code}`
+pub(crate) fn rooms_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let create = warp::post()
+        .and(warp::path!("rooms"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(client_addr())
+        .and(with_state(state.clone()))
+        .and_then(create_room_handler);
+
+    let list = warp::get()
+        .and(warp::path!("rooms"))
+        .and(with_state(state.clone()))
+        .and_then(list_rooms_handler);
+
+    let details = warp::get()
+        .and(warp::path!("rooms" / String))
+        .and(with_state(state))
+        .and_then(room_handler);
+
+    create.or(list).or(details)
+}
+
+async fn create_room_handler(
+    request: CreateRoom,
+    client: ClientAddr,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    if !request.version.as_deref().is_none_or(valid_version) {
+        return Ok(Box::new(StatusCode::BAD_REQUEST) as Box<dyn Reply>);
+    }
+    let mut state = state.lock().await;
+    let ip = client.ip(state.config.rate_limits.trust_forwarded_for);
+    if let Some(ip) = ip {
+        if state.take_room_token(ip) != Limit::Allowed {
+            warn!("Too many rooms created from {}", ip);
+            return Ok(Box::new(StatusCode::TOO_MANY_REQUESTS));
+        }
+    }
+    let room = state.create_room(
+        request.max.map(NonZeroUsize::get),
+        request.version,
+        request.public,
+    );
+    let reply: Box<dyn Reply> = match room {
+        Some(room) => Box::new(warp::reply::with_status(
+            warp::reply::json(&room),
+            StatusCode::CREATED,
+        )),
+        None => Box::new(StatusCode::SERVICE_UNAVAILABLE),
+    };
+    Ok(reply)
+}
+
+async fn list_rooms_handler(
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let mut rooms = state.lock().await.public_rooms();
+    rooms.sort_by(|a, b| a.code.cmp(&b.code));
+    Ok(warp::reply::json(&rooms))
+}
+
+async fn room_handler(
+    code: String,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let reply: Box<dyn Reply> = match state.lock().await.room_info(&code) {
+        Some(room) => Box::new(warp::reply::json(&room)),
+        None => Box::new(StatusCode::NOT_FOUND),
+    };
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+    use crate::{
+        config::{Config, RateLimits},
+        rooms::{rooms_filter, RoomInfo, CODE_LENGTH},
+        signaling::State,
+    };
+
+    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        rooms_filter(Default::default())
+    }
+
+    #[tokio::test]
+    async fn create_and_get_room() {
+        let api = api();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/rooms")
+            .json(&serde_json::json!({ "max": 2, "version": "1" }))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: RoomInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(created.code.len(), CODE_LENGTH);
+        assert_eq!(created.capacity, Some(2));
+        assert_eq!(created.occupancy, 0);
+
+        let response = warp::test::request()
+            .path(&format!("/rooms/{}", created.code))
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let room: RoomInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(room, created);
+
+        let response = warp::test::request().path("/rooms/nope").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn list_public_rooms() {
+        let api = api();
+
+        for public in [true, false, true] {
+            warp::test::request()
+                .method("POST")
+                .path("/rooms")
+                .json(&serde_json::json!({ "public": public }))
+                .reply(&api)
+                .await;
+        }
+
+        let response = warp::test::request().path("/rooms").reply(&api).await;
+        let rooms: Vec<RoomInfo> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(rooms.len(), 2);
+        assert!(rooms.iter().all(|room| room.public));
+    }
+
+    #[tokio::test]
+    async fn room_creation_is_rate_limited() {
+        let api = rooms_filter(Arc::new(Mutex::new(State::new(Config {
+            rate_limits: RateLimits {
+                rooms_per_minute: 1.,
+                room_burst: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        }))));
+        let create = |ip: [u8; 4]| {
+            warp::test::request()
+                .method("POST")
+                .path("/rooms")
+                .remote_addr((ip, 1234).into())
+                .json(&serde_json::json!({}))
+                .reply(&api)
+        };
+
+        assert_eq!(create([10, 0, 0, 1]).await.status(), StatusCode::CREATED);
+        assert_eq!(create([10, 0, 0, 1]).await.status(), StatusCode::CREATED);
+        assert_eq!(
+            create([10, 0, 0, 1]).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // other ips have their own bucket
+        assert_eq!(create([10, 0, 0, 2]).await.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn invalid_room_version() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/rooms")
+            .json(&serde_json::json!({ "version": "1 2" }))
+            .reply(&api())
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,13 +1,42 @@
-use futures::{lock::Mutex, stream::SplitSink, StreamExt};
+use futures::{
+    lock::Mutex,
+    stream::{SplitSink, SplitStream},
+    StreamExt,
+};
 use log::{error, info, warn};
+use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     convert::Infallible,
-    sync::Arc,
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
 };
-use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+    admin::{AdminRoomInfo, PeerInfo},
+    auth::{self, TokenError},
+    config::{Config, RateLimits, RoomLifetime},
+    encoding::{DecodeError, Encoded, Encoding, MSGPACK_PROTOCOL},
+    matchmaking::{find_matches, Candidate},
+    metrics::Metrics,
+    rate_limit::{client_addr, ClientAddr, IpState, Limit, TokenBucket},
+    room_state::RoomState,
+    rooms::{create_code, RoomInfo},
+};
 use warp::{
+    http::StatusCode,
     ws::{Message, WebSocket},
     Error, Filter, Rejection, Reply,
 };
@@ -17,18 +46,160 @@ pub mod matchbox {
 
     pub type PeerId = String;
 
+    /// A change to the key-value state of a room
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct StateChange<S> {
+        /// The peer whose namespace the key is in, `None` for room-wide keys
+        pub peer: Option<PeerId>,
+        pub key: String,
+        /// `None` if the key was removed
+        pub value: Option<S>,
+    }
+
     /// Requests go from peer to signalling server
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     pub enum PeerRequest<S> {
+        /// Legacy clients pick their own id and join once they sent it. Clients that
+        /// connect with `?protocol=2` get theirs assigned, see [`PeerEvent::IdAssigned`]
         Uuid(PeerId),
-        Signal { receiver: PeerId, data: S },
+        Signal {
+            receiver: PeerId,
+            data: S,
+        },
+        /// Host only: makes another peer in the room the host
+        TransferHost(PeerId),
+        /// Host only: settings all peers in the room should use, e.g. the map.
+        /// The server passes them on without looking at them
+        SetRoomSettings(S),
+        /// Sends `data` to everyone in the room through the server, e.g. lobby chat
+        /// before peer-to-peer connections exist
+        Broadcast(S),
+        /// Sets a key in the peer's own namespace of the room state, `None` removes it
+        SetState {
+            key: String,
+            value: Option<S>,
+        },
+        /// Host only: sets a room-wide key of the room state, `None` removes it
+        SetRoomState {
+            key: String,
+            value: Option<S>,
+        },
+        /// Whether the peer is ready to start, the game starts once everyone in the room is
+        Ready(bool),
+        /// Host only: seats the players in the id room in the order they joined
+        AssignSeats,
     }
 
     /// Events go from signalling server to peer
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     pub enum PeerEvent<S> {
+        /// The first event a peer receives once it joined its room, carrying its
+        /// server-assigned id
+        IdAssigned(PeerId),
+        /// The requested room has no space left, the connection is closed afterwards
+        RoomFull,
+        /// The requested room was created by a client with a different version,
+        /// the connection is closed afterwards
+        VersionMismatch {
+            room_version: Option<String>,
+        },
         NewPeer(PeerId),
-        Signal { sender: PeerId, data: S },
+        PeerLeft(PeerId),
+        Signal {
+            sender: PeerId,
+            data: S,
+        },
+        Error {
+            code: ErrorCode,
+            message: String,
+        },
+        /// The server is shutting down and closes the connection, clients should
+        /// reconnect after the given delay
+        ServerShutdown {
+            reconnect_after_ms: u64,
+        },
+        /// An admin removed the peer or closed its room, the connection is closed afterwards
+        Kicked {
+            reason: String,
+        },
+        /// The peer that decides for the room. Sent when joining a room that has a host,
+        /// and to everyone in the room whenever the host changes
+        HostChanged(PeerId),
+        /// Settings of the room, sent when joining and whenever the host changes them
+        RoomSettings(S),
+        /// Data a peer in the room broadcast, including our own broadcasts
+        Broadcast {
+            sender: PeerId,
+            data: S,
+        },
+        /// Changes to the key-value state of the room. Peers joining a room with state
+        /// receive all of it as a `snapshot`, which replaces whatever they knew before
+        RoomState {
+            snapshot: bool,
+            changes: Vec<StateChange<S>>,
+        },
+        /// Everyone in the room is ready. Peers should start `start_in_ms` milliseconds
+        /// after receiving this, with the players in `seat_order`. A delay rather than a
+        /// point in time, so it doesn't matter whether clocks agree. Peers have to be
+        /// ready again for another game
+        StartGame {
+            seat_order: Vec<PeerId>,
+            start_in_ms: u64,
+        },
+        /// No match was found for a next_N room in time, the connection is closed
+        /// afterwards
+        MatchmakingTimeout,
+        /// A next_N room was finished with fewer players than requested, because
+        /// someone in it waited long enough
+        MatchFormed {
+            peers: Vec<PeerId>,
+        },
+        /// The final seat order of the room, for the game session. Sent when a next_N
+        /// room is formed, or the host of an id room assigns seats
+        Seats(Vec<PeerId>),
+        /// A spectator joined the room. Sent to players only; spectators are not told
+        /// about players joining, the designated player connects to them instead
+        NewSpectator(PeerId),
+        /// Sent after `IdAssigned`. Peers that lose their connection can join again with
+        /// `?resume=<token>` for a while, to keep their id and place in the room
+        ResumeToken(String),
+        /// A peer in the room lost its connection and resumed its session
+        PeerReconnected(PeerId),
+    }
+
+    /// Reasons for the signalling server to reject a request
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum ErrorCode {
+        /// The request could not be parsed
+        MalformedRequest,
+        /// The peer sent a uuid, but it has an id already
+        DuplicateUuid,
+        /// The receiver of a signal is not connected
+        UnknownReceiver,
+        /// The room reached its maximum lifetime and was closed
+        RoomExpired,
+        /// The peer did not answer pings or sent nothing for too long
+        Timeout,
+        /// The server can't create any more rooms
+        TooManyRooms,
+        /// The requested room exceeds the server's limits, e.g. next_N with too many players
+        InvalidRoom,
+        /// The peer sends messages faster than allowed, the message was dropped
+        Throttled,
+        /// The peer kept sending messages faster than allowed and is disconnected
+        RateLimited,
+        /// Too many connections from the same ip, the connection is closed afterwards
+        TooManyConnections,
+        /// The message exceeds the maximum message size and was dropped
+        MessageTooLarge,
+        /// Only the host of a room may send this request
+        NotHost,
+        /// The peer is still waiting for its next_N room to fill up
+        NotInRoom,
+        /// The peer's namespace of the room state, or the room-wide one, has too many keys
+        TooManyKeys,
+        /// Spectators can't send this request, e.g. get ready
+        Spectator,
     }
 }
 use matchbox::*;
@@ -42,423 +213,4456 @@ pub(crate) enum RequestedRoom {
     Next(usize),
 }
 
+impl RequestedRoom {
+    /// The room as it appears in the url, e.g. `ABCDE` or `next_2`
+    pub fn path(&self) -> String {
+        match self {
+            RequestedRoom::Id(id) => id.clone(),
+            RequestedRoom::Next(num_players) => format!("next_{}", num_players),
+        }
+    }
+}
+
+/// Longest `version` clients may send
+const MAX_VERSION_LEN: usize = 64;
+
+/// Versions end up as map keys and metric labels, so they are kept short and printable
+pub(crate) fn valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= MAX_VERSION_LEN
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._+-".contains(c))
+}
+
+/// Options passed as query parameters along with the room, e.g. `/ABCDE?max=2`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub(crate) struct RoomOptions {
+    /// Maximum number of peers in an id room, decided by whoever creates the room
+    pub max: Option<NonZeroUsize>,
+    /// Game or protocol version of the client, only peers with the same version are
+    /// put in a room together. Up to 64 letters, digits and `._+-`
+    pub version: Option<String>,
+    /// Join token, required if the server has a token secret.
+    /// May be passed as `Sec-WebSocket-Protocol: token.<token>` instead
+    pub token: Option<String>,
+    /// Rating of the player. Peers joining next_N with a rating are matched with
+    /// players of a similar rating, rather than whoever comes next
+    pub rating: Option<u32>,
+    /// How long to wait for a next_N room, e.g. `30s`. Afterwards the room is finished
+    /// with whoever is there, if there are at least `min_players`
+    #[serde(default, with = "humantime_serde")]
+    pub max_wait: Option<Duration>,
+    /// The fewest players a next_N room may be finished with, the full room by default
+    pub min_players: Option<NonZeroUsize>,
+    #[serde(default)]
+    pub role: Role,
+    /// Resume token of a session whose connection was lost. The room of the session is
+    /// rejoined, whatever the path says
+    pub resume: Option<String>,
+    /// Version of the signalling protocol the client talks, see [`PROTOCOL_VERSION`].
+    /// Clients that don't send it pick their own id with `PeerRequest::Uuid` and only
+    /// understand `NewPeer` and `Signal` events
+    pub protocol: Option<u32>,
+}
+
+/// The signalling protocol with server-assigned ids and all of [`PeerEvent`]
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+
+impl RoomOptions {
+    /// Whether the client predates [`PROTOCOL_VERSION`]
+    fn legacy(&self) -> bool {
+        self.protocol
+            .is_none_or(|protocol| protocol < PROTOCOL_VERSION)
+    }
+}
+
+/// Whether a peer joins a room to play or to watch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    #[default]
+    Player,
+    /// Spectators don't take a seat or count towards the size of a room
+    Spectator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RoomRequest {
+    pub room: RequestedRoom,
+    pub options: RoomOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum JoinError {
+    RoomFull,
+    TooManyRooms,
+    InvalidRoom,
+    VersionMismatch(Option<String>),
+}
+
+type Outgoing = std::result::Result<Message, warp::Error>;
+
+/// Sends messages to a peer's websocket, events in the encoding the peer talks
+#[derive(Debug, Clone)]
+pub(crate) struct PeerSender {
+    messages: mpsc::UnboundedSender<Outgoing>,
+    encoding: Encoding,
+    /// Legacy clients fail on any event but `NewPeer` and `Signal`
+    legacy: bool,
+}
+
+impl PeerSender {
+    fn send(&self, message: Outgoing) -> std::result::Result<(), mpsc::error::SendError<Outgoing>> {
+        self.messages.send(message)
+    }
+
+    fn understands(&self, event: &PeerEvent) -> bool {
+        !self.legacy || matches!(event, PeerEvent::NewPeer(_) | PeerEvent::Signal { .. })
+    }
+}
+
 pub(crate) struct Peer {
     pub uuid: PeerId,
     pub room: RequestedRoom,
-    pub sender:
-        Option<tokio::sync::mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>,
+    /// Set once a `Next` room this peer is waiting in has been filled
+    pub matched_room: Option<usize>,
+    pub version: Option<String>,
+    /// Player identity from the join token
+    pub player: Option<String>,
+    /// Set for peers in a rated next_N room
+    pub rating: Option<u32>,
+    /// How long the peer waits for a next_N room
+    pub max_wait: Option<Duration>,
+    /// The fewest players the peer accepts in a next_N room
+    pub min_players: Option<usize>,
+    pub spectator: bool,
+    pub ip: Option<IpAddr>,
+    pub connected_at: Instant,
+    pub messages: Arc<MessageCounts>,
+    pub sender: Option<PeerSender>,
+    /// Ends the peer's current connection, see [`State::kick`]
+    pub kick: Option<oneshot::Sender<Disconnect>>,
+    /// Lets the peer resume its session after losing its connection
+    pub resume_token: Option<String>,
+    /// How often the peer resumed its session
+    pub connection: u64,
+}
+
+/// Why the server ends a peer's connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Disconnect {
+    /// Sends a `Kicked` event first
+    Kicked(String),
+    /// The peer resumed its session on a new connection
+    Resumed,
+}
+
+/// How a peer's connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Departure {
+    /// The peer left, or the server made it leave
+    Left,
+    /// The connection was lost, the peer may still resume its session
+    Lost,
+}
+
+/// Messages received from and sent to a peer, not counting pings and pongs
+#[derive(Debug, Default)]
+pub(crate) struct MessageCounts {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+}
+
+/// Peers of a room that is playing together, either an id room or a filled next_N room
+#[derive(Debug, Default)]
+pub(crate) struct Lobby {
+    peers: HashSet<PeerId>,
+    host: Option<PeerId>,
+    settings: Option<serde_json::Value>,
+    state: RoomState,
+    /// Peers that are ready to start the game
+    ready: HashSet<PeerId>,
+    /// Seat order, once the room is formed. Peers that left keep their seat
+    seats: Option<Vec<PeerId>>,
+    /// Members of `peers` that only watch
+    spectators: HashSet<PeerId>,
+}
+
+impl Lobby {
+    /// Members that take part in the game, everyone but the spectators
+    fn players(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers
+            .iter()
+            .filter(move |peer_id| !self.spectators.contains(*peer_id))
+    }
+}
+
+/// Why a host-only request was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostError {
+    NotHost,
+    /// The new host is not in the room
+    UnknownPeer,
+}
+
+/// Why a change to the room state was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StateError {
+    NotInRoom,
+    NotHost,
+    TooManyKeys,
+    Spectator,
+}
+
+pub(crate) struct IdRoom {
+    lobby: Lobby,
+    capacity: Option<usize>,
+    version: Option<String>,
+    /// Public rooms are listed by the http api
+    public: bool,
+    created_at: Instant,
+    last_activity: Instant,
+}
+
+impl IdRoom {
+    fn new(capacity: Option<usize>, version: Option<String>) -> Self {
+        let now = Instant::now();
+        IdRoom {
+            lobby: Default::default(),
+            capacity,
+            version,
+            public: false,
+            created_at: now,
+            last_activity: now,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        match self.capacity {
+            Some(capacity) => self.lobby.players().count() >= capacity,
+            None => false,
+        }
+    }
+
+    fn info(&self, code: &str) -> RoomInfo {
+        RoomInfo {
+            code: code.to_string(),
+            occupancy: self.lobby.players().count(),
+            capacity: self.capacity,
+            version: self.version.clone(),
+            public: self.public,
+        }
+    }
+
+    fn is_expired(&self, now: Instant, lifetime: &RoomLifetime) -> bool {
+        if let Some(max_lifetime) = lifetime.max_lifetime {
+            if now - self.created_at >= max_lifetime {
+                return true;
+            }
+        }
+        self.lobby.peers.is_empty() && now - self.last_activity >= lifetime.empty_ttl
+    }
 }
 
 #[derive(Default)]
 pub(crate) struct State {
     clients: HashMap<PeerId, Peer>,
-    next_rooms: HashMap<usize, HashSet<PeerId>>,
-    id_rooms: HashMap<String, HashSet<PeerId>>,
+    /// Peers waiting for a room to fill up, by version and number of players
+    next_rooms: HashMap<(Option<String>, usize), HashSet<PeerId>>,
+    /// Peers waiting for a rated next_N room, by version and number of players
+    rated_queues: HashMap<(Option<String>, usize), HashSet<PeerId>>,
+    /// Peers by resume token
+    resume_tokens: HashMap<String, PeerId>,
+    matched_rooms: HashMap<usize, Lobby>,
+    next_matched_room: usize,
+    id_rooms: HashMap<String, IdRoom>,
+    /// Connections and message rate of every ip with connected peers
+    ips: HashMap<IpAddr, IpState>,
+    /// Rooms created with `POST /rooms` by ip, whether the ip is connected or not
+    room_creations: HashMap<IpAddr, TokenBucket>,
+    /// Set once the server is shutting down, no new peers are accepted from then on
+    shutting_down: bool,
+    pub config: Config,
+    pub metrics: Metrics,
 }
 
 impl State {
-    /// Returns peers already in room
-    fn add_peer(&mut self, peer: Peer) -> Vec<PeerId> {
-        let peer_id = peer.uuid.clone();
-        let room = peer.room.clone();
-        self.clients.insert(peer.uuid.clone(), peer);
+    pub(crate) fn new(config: Config) -> Self {
+        State {
+            config,
+            ..Default::default()
+        }
+    }
 
-        match room {
-            RequestedRoom::Id(room_id) => {
-                let peers = self.id_rooms.entry(room_id).or_default();
-                let ret = peers.iter().cloned().collect();
-                peers.insert(peer_id);
-                ret
-            }
-            RequestedRoom::Next(num_players) => {
-                let peers = self.next_rooms.entry(num_players).or_default();
-                let ret = peers.iter().cloned().collect();
-                if peers.len() == num_players - 1 {
-                    peers.clear() // the room is complete, we can forget about it now
-                } else {
-                    peers.insert(peer_id);
-                }
-                ret
-            }
+    /// Capacity of a new id room, given the capacity requested by its creator
+    fn room_capacity(&self, requested: Option<usize>) -> Option<usize> {
+        match (requested, self.config.max_peers_per_room) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
         }
     }
 
-    fn remove_peer(&mut self, peer_id: &PeerId) {
-        let peer = self
-            .clients
-            .remove(peer_id)
-            .expect("Couldn't find uuid to remove");
+    fn can_create_room(&self) -> bool {
+        match self.config.max_rooms {
+            Some(max_rooms) => self.id_rooms.len() < max_rooms,
+            None => true,
+        }
+    }
 
-        let room_peers = match peer.room {
-            RequestedRoom::Id(room_id) => self.id_rooms.get_mut(&room_id),
-            RequestedRoom::Next(num_players) => self.next_rooms.get_mut(&num_players),
-        };
+    fn connections_from(&self, ip: IpAddr) -> usize {
+        self.ips.get(&ip).map_or(0, |ip_state| ip_state.connections)
+    }
 
-        if let Some(room_peers) = room_peers {
-            room_peers.remove(peer_id);
+    /// Counts a new connection from `ip`. Returns false if there are too many already
+    fn connect_ip(&mut self, ip: IpAddr) -> bool {
+        let limits = self.config.rate_limits;
+        let ip_state = self.ips.entry(ip).or_insert_with(|| IpState {
+            connections: 0,
+            bucket: TokenBucket::new(limits.ip_messages_per_second, limits.ip_burst),
+        });
+        if ip_state.connections >= limits.max_connections_per_ip {
+            return false;
         }
+        ip_state.connections += 1;
+        true
     }
 
-    fn try_send(&self, id: &PeerId, message: Message) {
-        let peer = self.clients.get(id);
-        let peer = match peer {
-            Some(peer) => peer,
-            None => {
-                error!("Unknown peer {:?}", id);
-                return;
+    fn disconnect_ip(&mut self, ip: IpAddr) {
+        if let Some(ip_state) = self.ips.get_mut(&ip) {
+            ip_state.connections -= 1;
+            if ip_state.connections == 0 {
+                self.ips.remove(&ip);
             }
-        };
-        if let Err(e) = peer.sender.as_ref().unwrap().send(Ok(message.clone())) {
-            error!("Error sending message {:?}", e);
         }
     }
-}
 
-fn parse_room_id(id: String) -> RequestedRoom {
-    match id.strip_prefix("next_").and_then(|n| n.parse().ok()) {
-        Some(num_players) => RequestedRoom::Next(num_players),
-        None => RequestedRoom::Id(id),
+    /// Takes a token from the bucket for creating rooms from `ip`
+    pub(crate) fn take_room_token(&mut self, ip: IpAddr) -> Limit {
+        let limits = self.config.rate_limits;
+        self.room_creations
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(limits.rooms_per_minute / 60., limits.room_burst))
+            .take()
     }
-}
 
-pub(crate) fn ws_filter(
-    state: Arc<Mutex<State>>,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::ws()
-        .and(warp::any())
-        .and(warp::path::param().map(parse_room_id))
-        .and(with_state(state.clone()))
-        .and_then(ws_handler)
-}
+    /// Takes a token from the bucket shared by all connections from `ip`
+    fn take_ip_token(&mut self, ip: IpAddr) -> Limit {
+        match self.ips.get_mut(&ip) {
+            Some(ip_state) => ip_state.bucket.take(),
+            None => Limit::Allowed,
+        }
+    }
 
-fn with_state(
-    state: Arc<Mutex<State>>,
-) -> impl Filter<Extract = (Arc<Mutex<State>>,), Error = Infallible> + Clone {
-    warp::any().map(move || state.clone())
-}
+    /// Returns peers already in room
+    fn add_peer(&mut self, peer: Peer, options: &RoomOptions) -> Result<Vec<PeerId>, JoinError> {
+        let peer_id = peer.uuid.clone();
+        let room = peer.room.clone();
 
-pub(crate) async fn ws_handler(
-    ws: warp::ws::Ws,
-    requested_room: RequestedRoom,
-    state: Arc<Mutex<State>>,
-) -> std::result::Result<impl Reply, Rejection> {
-    Ok(ws.on_upgrade(move |websocket| handle_ws(websocket, state, requested_room)))
-}
+        match &room {
+            RequestedRoom::Id(room_id) => match self.id_rooms.get(room_id) {
+                Some(room) => {
+                    if room.version != peer.version {
+                        return Err(JoinError::VersionMismatch(room.version.clone()));
+                    }
+                    if room.is_full() {
+                        return Err(JoinError::RoomFull);
+                    }
+                }
+                None if !self.can_create_room() => return Err(JoinError::TooManyRooms),
+                None => {}
+            },
+            RequestedRoom::Next(num_players) => {
+                let max = self.config.max_peers_per_room.unwrap_or(usize::MAX);
+                if *num_players == 0 || *num_players > max {
+                    return Err(JoinError::InvalidRoom);
+                }
+                if peer.min_players.is_some_and(|min| min > *num_players) {
+                    return Err(JoinError::InvalidRoom);
+                }
+            }
+        }
+        let capacity = self.room_capacity(options.max.map(NonZeroUsize::get));
+        let version = peer.version.clone();
+        let spectator = peer.spectator;
+        if let Some(token) = &peer.resume_token {
+            self.resume_tokens.insert(token.clone(), peer_id.clone());
+        }
 
-#[derive(Debug, thiserror::Error)]
-enum RequestError {
-    #[error("Warp error")]
-    WarpError(#[from] warp::Error),
-    #[error("Text error")]
-    TextError,
-    #[error("Json error")]
-    JsonError(#[from] serde_json::Error),
-}
+        self.clients.insert(peer.uuid.clone(), peer);
 
-fn parse_request(request: Result<Message, Error>) -> Result<PeerRequest, RequestError> {
-    let request = request?;
+        let peers = match room {
+            RequestedRoom::Id(room_id) => {
+                let room = self.id_rooms.entry(room_id).or_insert_with_key(|room_id| {
+                    info!("Room {:?} created", room_id);
+                    IdRoom::new(capacity, version)
+                });
+                room.last_activity = Instant::now();
+                let ret = room.lobby.players().cloned().collect();
+                if spectator {
+                    room.lobby.spectators.insert(peer_id.clone());
+                } else if room.lobby.host.is_none() {
+                    room.lobby.host = Some(peer_id.clone());
+                }
+                room.lobby.peers.insert(peer_id);
+                ret
+            }
+            RequestedRoom::Next(num_players) => self.enqueue(peer_id, num_players),
+        };
 
-    if !request.is_text() {
-        warn!("Got non-text request: {:?}", request);
-        return Err(RequestError::TextError);
+        self.forget_empty_queues();
+        self.update_metrics();
+        Ok(peers)
     }
 
-    let request = request.to_str().map_err(|e| {
-        warn!("Error trying to interpret message as string: {:?}", e);
-        RequestError::TextError
-    })?;
+    /// Queues a known peer for a next_N room, and fills the room if the peer completes it.
+    /// Returns the peers that were waiting already
+    fn enqueue(&mut self, peer_id: PeerId, num_players: usize) -> Vec<PeerId> {
+        let peer = &self.clients[&peer_id];
+        let key = (peer.version.clone(), num_players);
+        let spectator = peer.spectator;
+        // peers in rated rooms only meet once they are matched, see `matchmake`
+        if peer.rating.is_some() {
+            self.rated_queues.entry(key).or_default().insert(peer_id);
+            return vec![];
+        }
+        let clients = &self.clients;
+        let peers = self.next_rooms.entry(key).or_default();
+        // spectators wait along, but don't fill the room
+        let ret: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer_id| clients.get(*peer_id).is_some_and(|peer| !peer.spectator))
+            .cloned()
+            .collect();
+        if !spectator && ret.len() == num_players - 1 {
+            // the room is complete, remember who is in it so we can tell the others
+            // when someone leaves, then forget about the waiting room
+            let mut members = std::mem::take(peers);
+            members.insert(peer_id);
+            self.create_matched_room(members, num_players);
+        } else {
+            peers.insert(peer_id);
+        }
+        ret
+    }
 
-    let request: PeerRequest = serde_json::from_str(request)?;
+    /// Takes a peer waiting for a next_N room out of its queue.
+    /// Returns the peers waiting along with it, who know about it
+    fn dequeue(&mut self, peer_id: &PeerId) -> Vec<PeerId> {
+        let key = match self.queue_key(peer_id) {
+            Some(key) => key,
+            None => return vec![],
+        };
+        if let Some(queue) = self.rated_queues.get_mut(&key) {
+            queue.remove(peer_id);
+        }
+        let waiting = match self.next_rooms.get_mut(&key) {
+            Some(queue) if queue.contains(peer_id) => {
+                queue.remove(peer_id);
+                queue.iter().cloned().collect()
+            }
+            _ => vec![],
+        };
+        self.forget_empty_queues();
+        self.update_metrics();
+        waiting
+    }
 
-    Ok(request)
-}
+    /// Puts a peer that resumed its session back into the queue it was taken out of,
+    /// see [`State::suspend_peer`]. Returns the peers that were waiting already, `None`
+    /// if the peer is not waiting for a next_N room or still queued
+    fn requeue(&mut self, peer_id: &PeerId) -> Option<Vec<PeerId>> {
+        let key = self.queue_key(peer_id)?;
+        let queued = self
+            .next_rooms
+            .get(&key)
+            .into_iter()
+            .chain(self.rated_queues.get(&key))
+            .any(|queue| queue.contains(peer_id));
+        if queued {
+            return None;
+        }
+        let peers = self.enqueue(peer_id.clone(), key.1);
+        self.update_metrics();
+        Some(peers)
+    }
 
-fn spawn_sender_task(
-    sender: SplitSink<WebSocket, Message>,
-) -> mpsc::UnboundedSender<std::result::Result<Message, warp::Error>> {
-    let (client_sender, receiver) = mpsc::unbounded_channel();
-    tokio::task::spawn(UnboundedReceiverStream::new(receiver).forward(sender));
-    client_sender
-}
+    /// The queue of a peer that waits for a next_N room to be filled
+    fn queue_key(&self, peer_id: &PeerId) -> Option<(Option<String>, usize)> {
+        let peer = self.clients.get(peer_id)?;
+        match (&peer.room, peer.matched_room) {
+            (RequestedRoom::Next(num_players), None) => Some((peer.version.clone(), *num_players)),
+            _ => None,
+        }
+    }
 
-async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_room: RequestedRoom) {
-    let (ws_sender, mut ws_receiver) = websocket.split();
-    let sender = spawn_sender_task(ws_sender);
-    let mut peer_uuid = None;
+    /// Drops next_N queues nobody waits in anymore. They are keyed by the versions
+    /// clients send, so they would pile up otherwise
+    fn forget_empty_queues(&mut self) {
+        self.next_rooms.retain(|_, peers| !peers.is_empty());
+        self.rated_queues.retain(|_, peers| !peers.is_empty());
+    }
 
-    while let Some(request) = ws_receiver.next().await {
-        let request = match parse_request(request) {
-            Ok(request) => request,
-            Err(RequestError::WarpError(e)) => {
-                error!("Warp error while receiving request: {:?}", e);
-                // Most likely a ConnectionReset or similar.
-                // just give up on this peer.
-                break;
-            }
-            Err(e) => {
-                error!("Error untangling request: {:?}", e);
-                continue;
+    /// Turns the members of a filled next_N room into a matched room.
+    /// Returns the players in the order they connected
+    fn create_matched_room(&mut self, members: HashSet<PeerId>, num_players: usize) -> Vec<PeerId> {
+        let matched_room = self.next_matched_room;
+        self.next_matched_room += 1;
+        let wait_time = self
+            .metrics
+            .matchmaking_wait_seconds
+            .with_label_values(&[&num_players.to_string()]);
+        for member in &members {
+            if let Some(peer) = self.clients.get_mut(member) {
+                peer.matched_room = Some(matched_room);
+                wait_time.observe(peer.connected_at.elapsed().as_secs_f64());
             }
-        };
+        }
+        let spectators: HashSet<PeerId> = members
+            .iter()
+            .filter(|peer_id| {
+                self.clients
+                    .get(*peer_id)
+                    .is_some_and(|peer| peer.spectator)
+            })
+            .cloned()
+            .collect();
+        let players = self.in_connection_order(
+            members
+                .iter()
+                .filter(|peer_id| !spectators.contains(*peer_id)),
+        );
+        self.matched_rooms.insert(
+            matched_room,
+            Lobby {
+                peers: members,
+                // whoever waited longest decides
+                host: players.first().cloned(),
+                seats: Some(players.clone()),
+                spectators,
+                ..Default::default()
+            },
+        );
+        players
+    }
 
-        info!("{:?} <- {:?}", peer_uuid, request);
+    /// The rated queue a peer is waiting in
+    fn rated_queue_mut(&mut self, peer_id: &PeerId) -> Option<&mut HashSet<PeerId>> {
+        let peer = self.clients.get(peer_id)?;
+        match (&peer.room, peer.matched_room, peer.rating) {
+            (RequestedRoom::Next(num_players), None, Some(_)) => {
+                let key = (peer.version.clone(), *num_players);
+                self.rated_queues.get_mut(&key)
+            }
+            _ => None,
+        }
+    }
 
-        match request {
-            PeerRequest::Uuid(id) => {
-                if peer_uuid.is_some() {
-                    error!("client set uuid more than once");
-                    continue;
+    /// Matches peers in rated queues whose rating windows overlap, and disconnects those
+    /// who waited too long
+    fn matchmake(&mut self) {
+        let now = Instant::now();
+        let keys: Vec<(Option<String>, usize)> = self.rated_queues.keys().cloned().collect();
+        for key in keys {
+            let candidates = self.rated_queues[&key]
+                .iter()
+                .filter_map(|peer_id| {
+                    let peer = self.clients.get(peer_id)?;
+                    Some(Candidate {
+                        id: peer_id.clone(),
+                        rating: peer.rating?,
+                        waited: now.saturating_duration_since(peer.connected_at),
+                    })
+                })
+                .collect();
+            for group in find_matches(candidates, key.1, &self.config.matchmaking) {
+                let members: HashSet<PeerId> = group.into_iter().map(|peer| peer.id).collect();
+                if let Some(queue) = self.rated_queues.get_mut(&key) {
+                    queue.retain(|peer_id| !members.contains(peer_id));
                 }
-                peer_uuid = Some(id.clone());
+                let members = self.create_matched_room(members, key.1);
+                info!("Matched {:?}", members);
+                self.announce_match(&members);
+            }
+        }
 
-                let mut state = state.lock().await;
-                let peers = state.add_peer(Peer {
-                    uuid: id.clone(),
-                    sender: Some(sender.clone()),
-                    room: requested_room.clone(),
-                });
+        let timed_out: Vec<PeerId> = self
+            .rated_queues
+            .values()
+            .flatten()
+            .filter(|peer_id| self.wait_deadline(peer_id).is_some_and(|at| at <= now))
+            .cloned()
+            .collect();
+        for queue in self.rated_queues.values_mut() {
+            queue.retain(|peer_id| !timed_out.contains(peer_id));
+        }
+        self.time_out(&timed_out);
 
-                let event = Message::text(
-                    serde_json::to_string(&PeerEvent::NewPeer(id.clone()))
-                        .expect("error serializing message"),
-                );
+        self.finish_next_rooms(now);
+        self.forget_empty_queues();
+        self.update_metrics();
+    }
 
-                for peer_id in peers {
-                    // Tell everyone about this new peer
-                    info!("{:?} -> {:?}", peer_id, event.to_str().unwrap());
-                    state.try_send(&peer_id, event.clone());
-                }
-            }
-            PeerRequest::Signal { receiver, data } => {
-                let sender = match peer_uuid.clone() {
-                    Some(sender) => sender,
+    /// Finishes unrated next_N rooms with fewer players once someone in them waited
+    /// long enough, or gives up on that peer if the others want a fuller room
+    fn finish_next_rooms(&mut self, now: Instant) {
+        let keys: Vec<(Option<String>, usize)> = self.next_rooms.keys().cloned().collect();
+        for key in keys {
+            let num_players = key.1;
+            loop {
+                // spectators keep waiting for a full room
+                let queued =
+                    self.in_connection_order(self.next_rooms[&key].iter().filter(|peer_id| {
+                        self.clients
+                            .get(*peer_id)
+                            .is_some_and(|peer| !peer.spectator)
+                    }));
+                let expired = queued
+                    .iter()
+                    .find(|peer_id| self.wait_deadline(peer_id).is_some_and(|at| at <= now));
+                let expired = match expired {
+                    Some(expired) => expired.clone(),
+                    None => break,
+                };
+                let min_players = |peer_id: &PeerId| {
+                    self.clients
+                        .get(peer_id)
+                        .and_then(|peer| peer.min_players)
+                        .unwrap_or(num_players)
+                };
+                // the biggest room the expired peer and everyone else in it accept
+                let members = (min_players(&expired)..num_players).rev().find_map(|size| {
+                    let others = queued
+                        .iter()
+                        .filter(|peer_id| **peer_id != expired && min_players(peer_id) <= size)
+                        .take(size - 1);
+                    let members: HashSet<PeerId> = others.chain([&expired]).cloned().collect();
+                    (members.len() == size).then_some(members)
+                });
+                let queue = self.next_rooms.get_mut(&key).expect("queue vanished");
+                match members {
+                    Some(members) => {
+                        queue.retain(|peer_id| !members.contains(peer_id));
+                        let left_behind: Vec<PeerId> = queue.iter().cloned().collect();
+                        let members = self.create_matched_room(members, num_players);
+                        info!("Finished room early with {:?}", members);
+                        for member in &members {
+                            self.send_to_all(&left_behind, &PeerEvent::PeerLeft(member.clone()));
+                        }
+                        // members were told about everyone waiting along with them
+                        for peer in &left_behind {
+                            self.send_to_all(&members, &PeerEvent::PeerLeft(peer.clone()));
+                        }
+                        let event = PeerEvent::MatchFormed {
+                            peers: members.clone(),
+                        };
+                        self.send_to_all(&members, &event);
+                        self.send_to_all(&members, &PeerEvent::HostChanged(members[0].clone()));
+                        self.send_to_all(&members, &PeerEvent::Seats(members.clone()));
+                    }
                     None => {
-                        error!("client is trying signal before sending uuid");
-                        continue;
+                        queue.remove(&expired);
+                        let remaining: Vec<PeerId> = queue.iter().cloned().collect();
+                        self.send_to_all(&remaining, &PeerEvent::PeerLeft(expired.clone()));
+                        self.time_out(&[expired]);
                     }
-                };
-                let event = Message::text(
-                    serde_json::to_string(&PeerEvent::Signal { sender, data })
-                        .expect("error serializing message"),
-                );
-                let state = state.lock().await;
-                if let Err(e) = state
-                    .clients
-                    .get(&receiver)
-                    .expect("Failed to get client for receiver")
-                    .sender
-                    .as_ref()
-                    .expect("No sender on client")
-                    .send(Ok(event))
-                {
-                    error!("error sending: {:?}", e);
                 }
             }
         }
     }
 
-    info!("Removing peer: {:?}", peer_uuid);
-    if let Some(uuid) = peer_uuid {
-        let mut state = state.lock().await;
-        state.remove_peer(&uuid);
+    /// When a peer waiting for a next_N room gives up, `None` if it waits forever
+    fn wait_deadline(&self, peer_id: &PeerId) -> Option<Instant> {
+        let peer = self.clients.get(peer_id)?;
+        let max_wait = match peer.rating {
+            Some(_) => peer.max_wait.or(Some(self.config.matchmaking.max_wait)),
+            None => peer.max_wait,
+        }?;
+        Some(peer.connected_at + max_wait)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Tells peers no match was found for them and disconnects them. They have to be
+    /// out of their queue already
+    fn time_out(&mut self, peers: &[PeerId]) {
+        self.send_to_all(peers, &PeerEvent::MatchmakingTimeout);
+        for peer_id in peers {
+            self.try_send(peer_id, Message::close());
+            self.forget_resume_token(peer_id);
+        }
+    }
 
-    use std::time::Duration;
+    /// Introduces the members of a new match to each other, the first member becomes host
+    fn announce_match(&self, members: &[PeerId]) {
+        for (i, member) in members.iter().enumerate() {
+            for other in &members[i + 1..] {
+                let event = PeerEvent::NewPeer(other.clone());
+                self.send_to_all(std::slice::from_ref(member), &event);
+            }
+        }
+        if let Some(host) = members.first() {
+            self.send_to_all(members, &PeerEvent::HostChanged(host.clone()));
+        }
+        self.send_to_all(members, &PeerEvent::Seats(members.to_vec()));
+    }
 
-    use futures::pin_mut;
-    use tokio::{select, time};
-    use warp::{test::WsClient, ws::Message, Filter, Rejection, Reply};
+    /// The spectators in the room of `peer_id`, or waiting for the same next_N room
+    fn room_spectators(&self, peer_id: &PeerId) -> Vec<PeerId> {
+        let peer = match self.clients.get(peer_id) {
+            Some(peer) => peer,
+            None => return vec![],
+        };
+        let members = match (&peer.room, peer.matched_room) {
+            (RequestedRoom::Next(_), None) if peer.rating.is_some() => None,
+            (RequestedRoom::Next(num_players), None) => {
+                let key = (peer.version.clone(), *num_players);
+                self.next_rooms.get(&key)
+            }
+            _ => self.lobby(peer_id).map(|lobby| &lobby.spectators),
+        };
+        members
+            .into_iter()
+            .flatten()
+            .filter(|member| *member != peer_id)
+            .filter(|member| self.clients.get(*member).is_some_and(|peer| peer.spectator))
+            .cloned()
+            .collect()
+    }
 
-    use crate::signaling::{parse_room_id, PeerEvent, RequestedRoom};
+    fn room_members_mut(&mut self, peer_id: &PeerId) -> Option<&mut HashSet<PeerId>> {
+        let peer = self.clients.get(peer_id)?;
+        match (&peer.room, peer.matched_room) {
+            // peers in rated queues don't know about each other
+            (RequestedRoom::Next(_), None) if peer.rating.is_some() => None,
+            (RequestedRoom::Next(num_players), None) => {
+                let key = (peer.version.clone(), *num_players);
+                self.next_rooms.get_mut(&key)
+            }
+            _ => self.lobby_mut(peer_id).map(|lobby| &mut lobby.peers),
+        }
+    }
 
-    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        super::ws_filter(Default::default())
+    /// The lobby of a peer, `None` while it waits for a next_N room to fill up
+    pub(crate) fn lobby(&self, peer_id: &PeerId) -> Option<&Lobby> {
+        let peer = self.clients.get(peer_id)?;
+        match (&peer.room, peer.matched_room) {
+            (RequestedRoom::Id(room_id), _) => self
+                .id_rooms
+                .get(room_id)
+                .map(|room| &room.lobby)
+                // the room may have been destroyed and its id reused
+                .filter(|lobby| lobby.peers.contains(peer_id)),
+            (RequestedRoom::Next(_), Some(matched_room)) => self.matched_rooms.get(&matched_room),
+            (RequestedRoom::Next(_), None) => None,
+        }
     }
 
-    #[tokio::test]
-    async fn ws_connect() {
-        let _ = pretty_env_logger::try_init();
-        let api = api();
+    fn lobby_mut(&mut self, peer_id: &PeerId) -> Option<&mut Lobby> {
+        let peer = self.clients.get(peer_id)?;
+        match (&peer.room, peer.matched_room) {
+            (RequestedRoom::Id(room_id), _) => self
+                .id_rooms
+                .get_mut(room_id)
+                .map(|room| &mut room.lobby)
+                .filter(|lobby| lobby.peers.contains(peer_id)),
+            (RequestedRoom::Next(_), Some(matched_room)) => {
+                self.matched_rooms.get_mut(&matched_room)
+            }
+            (RequestedRoom::Next(_), None) => None,
+        }
+    }
 
-        // let req = warp::test::ws().path("/echo");
-        warp::test::ws()
-            .path("/room_a")
-            .handshake(api)
-            // .handshake(ws_echo())
-            .await
-            .expect("handshake");
+    /// The peer that connected first, which becomes host when there is a choice
+    fn first_connected<'a>(&self, peers: impl Iterator<Item = &'a PeerId>) -> Option<PeerId> {
+        self.in_connection_order(peers).into_iter().next()
     }
 
-    #[tokio::test]
-    async fn new_peer() {
-        let _ = pretty_env_logger::try_init();
-        let api = api();
+    /// Sorts peers by when they connected, e.g. for a seat order everyone agrees on
+    fn in_connection_order<'a>(&self, peers: impl Iterator<Item = &'a PeerId>) -> Vec<PeerId> {
+        let mut peers: Vec<&Peer> = peers
+            .filter_map(|peer_id| self.clients.get(peer_id))
+            .collect();
+        peers.sort_by(|a, b| (a.connected_at, &a.uuid).cmp(&(b.connected_at, &b.uuid)));
+        peers.into_iter().map(|peer| peer.uuid.clone()).collect()
+    }
 
-        // let req = warp::test::ws().path("/echo");
-        let mut client_a = warp::test::ws()
-            .path("/room_a")
-            .handshake(api.clone())
+    /// Hands the host role of a leaving peer to the longest-connected other peer.
+    /// Returns the new host, if it changed
+    fn migrate_host(&mut self, leaving: &PeerId) -> Option<PeerId> {
+        let lobby = self.lobby(leaving)?;
+        if lobby.host.as_ref() != Some(leaving) {
+            return None;
+        }
+        let new_host = self.first_connected(lobby.players().filter(|peer| *peer != leaving));
+        self.lobby_mut(leaving)?.host = new_host.clone();
+        new_host
+    }
+
+    /// Makes `new_host` the host of the room of `peer_id`, who has to be the host.
+    /// Returns everyone in the room
+    fn transfer_host(
+        &mut self,
+        peer_id: &PeerId,
+        new_host: &PeerId,
+    ) -> Result<Vec<PeerId>, HostError> {
+        let lobby = self.lobby_mut(peer_id).ok_or(HostError::NotHost)?;
+        if lobby.host.as_ref() != Some(peer_id) {
+            return Err(HostError::NotHost);
+        }
+        if !lobby.peers.contains(new_host) || lobby.spectators.contains(new_host) {
+            return Err(HostError::UnknownPeer);
+        }
+        lobby.host = Some(new_host.clone());
+        Ok(lobby.peers.iter().cloned().collect())
+    }
+
+    /// Stores the settings of the room of `peer_id`, who has to be the host.
+    /// Returns everyone in the room
+    fn set_room_settings(
+        &mut self,
+        peer_id: &PeerId,
+        settings: serde_json::Value,
+    ) -> Result<Vec<PeerId>, HostError> {
+        let lobby = self.lobby_mut(peer_id).ok_or(HostError::NotHost)?;
+        if lobby.host.as_ref() != Some(peer_id) {
+            return Err(HostError::NotHost);
+        }
+        lobby.settings = Some(settings);
+        Ok(lobby.peers.iter().cloned().collect())
+    }
+
+    /// Seats everyone in the room of `peer_id`, who has to be the host, in the order they
+    /// connected. Returns the seats
+    fn assign_seats(&mut self, peer_id: &PeerId) -> Result<Vec<PeerId>, HostError> {
+        let lobby = self.lobby(peer_id).ok_or(HostError::NotHost)?;
+        if lobby.host.as_ref() != Some(peer_id) {
+            return Err(HostError::NotHost);
+        }
+        let seats = self.in_connection_order(lobby.players());
+        self.lobby_mut(peer_id).expect("lobby vanished").seats = Some(seats.clone());
+        Ok(seats)
+    }
+
+    /// The seat order to start a game with: the assigned seats of peers still in the room,
+    /// then everyone else in the order they connected
+    fn seat_order(&self, lobby: &Lobby) -> Vec<PeerId> {
+        let seated: Vec<PeerId> = lobby
+            .seats
+            .iter()
+            .flatten()
+            .filter(|peer_id| lobby.peers.contains(*peer_id))
+            .cloned()
+            .collect();
+        let unseated = lobby.players().filter(|peer_id| !seated.contains(peer_id));
+        let unseated = self.in_connection_order(unseated);
+        seated.into_iter().chain(unseated).collect()
+    }
+
+    /// Applies a change to the room state of `peer_id`, who has to be the host to change
+    /// room-wide keys. Returns everyone in the room
+    fn set_state(
+        &mut self,
+        peer_id: &PeerId,
+        change: &StateChange<serde_json::Value>,
+    ) -> Result<Vec<PeerId>, StateError> {
+        let max_keys = self.config.rate_limits.max_state_keys;
+        let lobby = self.lobby_mut(peer_id).ok_or(StateError::NotInRoom)?;
+        if lobby.spectators.contains(peer_id) {
+            return Err(StateError::Spectator);
+        }
+        if change.peer.is_none() && lobby.host.as_ref() != Some(peer_id) {
+            return Err(StateError::NotHost);
+        }
+        if !lobby.state.apply(change, max_keys) {
+            return Err(StateError::TooManyKeys);
+        }
+        Ok(lobby.peers.iter().cloned().collect())
+    }
+
+    /// Marks `peer_id` as ready or not. Once everyone in the room is ready, returns the
+    /// seat order of the game, in the order the peers connected
+    fn set_ready(
+        &mut self,
+        peer_id: &PeerId,
+        ready: bool,
+    ) -> Result<Option<Vec<PeerId>>, StateError> {
+        let lobby = self.lobby_mut(peer_id).ok_or(StateError::NotInRoom)?;
+        if lobby.spectators.contains(peer_id) {
+            return Err(StateError::Spectator);
+        }
+        if !ready {
+            lobby.ready.remove(peer_id);
+            return Ok(None);
+        }
+        lobby.ready.insert(peer_id.clone());
+        if lobby.players().any(|player| !lobby.ready.contains(player)) {
+            return Ok(None);
+        }
+        lobby.ready.clear();
+        let lobby = self.lobby(peer_id).expect("lobby vanished");
+        Ok(Some(self.seat_order(lobby)))
+    }
+
+    /// Removes the namespace of a leaving peer from its room state.
+    /// Returns the removed keys as changes
+    fn remove_peer_state(&mut self, leaving: &PeerId) -> Vec<StateChange<serde_json::Value>> {
+        match self.lobby_mut(leaving) {
+            Some(lobby) => lobby.state.remove_peer(leaving),
+            None => vec![],
+        }
+    }
+
+    /// Creates an empty id room with a unique, server-generated code.
+    /// Returns `None` if the server can't create any more rooms
+    pub(crate) fn create_room(
+        &mut self,
+        capacity: Option<usize>,
+        version: Option<String>,
+        public: bool,
+    ) -> Option<RoomInfo> {
+        if !self.can_create_room() {
+            return None;
+        }
+        let capacity = self.room_capacity(capacity);
+        let code = loop {
+            let code = create_code();
+            if !self.id_rooms.contains_key(&code) {
+                break code;
+            }
+        };
+        info!("Room {:?} created", code);
+
+        let mut room = IdRoom::new(capacity, version);
+        room.public = public;
+        let info = room.info(&code);
+        self.id_rooms.insert(code, room);
+        self.update_metrics();
+        Some(info)
+    }
+
+    pub(crate) fn room_info(&self, code: &str) -> Option<RoomInfo> {
+        self.id_rooms.get(code).map(|room| room.info(code))
+    }
+
+    pub(crate) fn public_rooms(&self) -> Vec<RoomInfo> {
+        self.id_rooms
+            .iter()
+            .filter(|(_, room)| room.public)
+            .map(|(code, room)| room.info(code))
+            .collect()
+    }
+
+    /// Marks the id room of the given peer as active
+    fn touch_room(&mut self, peer_id: &PeerId) {
+        if let Some(Peer {
+            room: RequestedRoom::Id(room_id),
+            ..
+        }) = self.clients.get(peer_id)
+        {
+            if let Some(room) = self.id_rooms.get_mut(room_id) {
+                room.last_activity = Instant::now();
+            }
+        }
+    }
+
+    /// Returns peers remaining in the room
+    fn remove_peer(&mut self, peer_id: &PeerId) -> Vec<PeerId> {
+        self.touch_room(peer_id);
+        if let Some(lobby) = self.lobby_mut(peer_id) {
+            lobby.ready.remove(peer_id);
+            lobby.spectators.remove(peer_id);
+        }
+        if let Some(queue) = self.rated_queue_mut(peer_id) {
+            queue.remove(peer_id);
+        }
+        let remaining = match self.room_members_mut(peer_id) {
+            // peers that timed out were taken out of their queue already
+            Some(room_peers) if room_peers.contains(peer_id) => {
+                room_peers.remove(peer_id);
+                room_peers.iter().cloned().collect()
+            }
+            _ => vec![],
+        };
+
+        let peer = self
+            .clients
+            .remove(peer_id)
+            .expect("Couldn't find uuid to remove");
+        if let Some(token) = &peer.resume_token {
+            self.resume_tokens.remove(token);
+        }
+
+        if let Some(matched_room) = peer.matched_room {
+            if remaining.is_empty() {
+                self.matched_rooms.remove(&matched_room);
+            }
+        }
+
+        self.forget_empty_queues();
+        self.update_metrics();
+        remaining
+    }
+
+    /// Sends `event` to each of `peers`
+    fn send_to_all(&self, peers: &[PeerId], event: &PeerEvent) {
+        let mut encoded = Encoded::new(event);
+        for peer_id in peers {
+            info!("{:?} -> {:?}", peer_id, event);
+            if let Some(sender) = self.peer_sender(peer_id).filter(|s| s.understands(event)) {
+                if let Err(e) = sender.send(Ok(encoded.message(sender.encoding))) {
+                    error!("Error sending message {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Every room with its members, including next_N rooms that are still filling up
+    pub(crate) fn admin_rooms(&self) -> Vec<AdminRoomInfo> {
+        let sorted = |peers: &HashSet<PeerId>| {
+            let mut peers: Vec<PeerId> = peers.iter().cloned().collect();
+            peers.sort();
+            peers
+        };
+        let id_rooms = self.id_rooms.iter().map(|(code, room)| AdminRoomInfo {
+            room: code.clone(),
+            matched_room: None,
+            version: room.version.clone(),
+            host: room.lobby.host.clone(),
+            peers: sorted(&room.lobby.peers),
+        });
+        let next_rooms = self
+            .next_rooms
+            .iter()
+            .chain(&self.rated_queues)
+            .filter(|(_, peers)| !peers.is_empty())
+            .map(|((version, num_players), peers)| AdminRoomInfo {
+                room: RequestedRoom::Next(*num_players).path(),
+                matched_room: None,
+                version: version.clone(),
+                host: None,
+                peers: sorted(peers),
+            });
+        let matched_rooms = self
+            .matched_rooms
+            .iter()
+            .filter_map(|(matched_room, lobby)| {
+                let peer = self.clients.get(lobby.peers.iter().next()?)?;
+                Some(AdminRoomInfo {
+                    room: peer.room.path(),
+                    matched_room: Some(*matched_room),
+                    version: peer.version.clone(),
+                    host: lobby.host.clone(),
+                    peers: sorted(&lobby.peers),
+                })
+            });
+
+        let mut rooms: Vec<AdminRoomInfo> =
+            id_rooms.chain(next_rooms).chain(matched_rooms).collect();
+        rooms.sort_by(|a, b| (&a.room, a.matched_room).cmp(&(&b.room, b.matched_room)));
+        rooms
+    }
+
+    pub(crate) fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        let peer = self.clients.get(peer_id)?;
+        Some(PeerInfo {
+            id: peer.uuid.clone(),
+            room: peer.room.path(),
+            matched_room: peer.matched_room,
+            version: peer.version.clone(),
+            player: peer.player.clone(),
+            spectator: peer.spectator,
+            ip: peer.ip,
+            connected_at: auth::unix_now().saturating_sub(peer.connected_at.elapsed().as_secs()),
+            messages_received: peer.messages.received.load(Ordering::Relaxed),
+            messages_sent: peer.messages.sent.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Disconnects a peer after sending it a `Kicked` event.
+    /// Returns false if there is no such peer
+    pub(crate) fn kick(&mut self, peer_id: &PeerId, reason: &str) -> bool {
+        match self.clients.get_mut(peer_id) {
+            Some(peer) => {
+                match peer.kick.take() {
+                    Some(kick) => {
+                        let _ = kick.send(Disconnect::Kicked(reason.to_string()));
+                    }
+                    // a peer that lost its connection can't be told, it just leaves
+                    None if peer.sender.is_none() => self.leave(peer_id),
+                    None => {}
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a peer from the server and tells everyone still in its room
+    fn leave(&mut self, peer_id: &PeerId) {
+        let new_host = self.migrate_host(peer_id);
+        let removed_state = self.remove_peer_state(peer_id);
+        let peers = self.remove_peer(peer_id);
+
+        // Tell everyone still in the room that this peer is gone
+        self.send_to_all(&peers, &PeerEvent::PeerLeft(peer_id.clone()));
+        if let Some(new_host) = new_host {
+            self.send_to_all(&peers, &PeerEvent::HostChanged(new_host));
+        }
+        if !removed_state.is_empty() {
+            let event = PeerEvent::RoomState {
+                snapshot: false,
+                changes: removed_state,
+            };
+            self.send_to_all(&peers, &event);
+        }
+    }
+
+    /// Finds the peer a resume token belongs to
+    fn resumable_peer(&self, token: &str) -> Option<PeerId> {
+        self.resume_tokens.get(token).cloned()
+    }
+
+    /// Moves a peer over to a new connection, ending the old one if it is still open.
+    /// Returns the number of the new connection
+    fn resume_peer(
+        &mut self,
+        peer_id: &PeerId,
+        sender: PeerSender,
+        kick: oneshot::Sender<Disconnect>,
+        messages: Arc<MessageCounts>,
+        ip: Option<IpAddr>,
+    ) -> u64 {
+        let peer = self
+            .clients
+            .get_mut(peer_id)
+            .expect("resume tokens belong to known peers");
+        if let Some(old_kick) = peer.kick.replace(kick) {
+            let _ = old_kick.send(Disconnect::Resumed);
+        }
+        peer.sender = Some(sender);
+        peer.messages = messages;
+        peer.ip = ip;
+        peer.connection += 1;
+        peer.connection
+    }
+
+    /// Keeps a peer that lost its connection around for the resume grace period.
+    /// Returns false if it can't resume its session and should leave right away
+    fn suspend_peer(&mut self, peer_id: &PeerId) -> bool {
+        if self.shutting_down || self.config.keepalive.resume_grace.is_zero() {
+            return false;
+        }
+        match self.clients.get_mut(peer_id) {
+            Some(peer) if peer.resume_token.is_some() => {
+                peer.sender = None;
+                peer.kick = None;
+                // nobody gets matched with a peer that may not come back, it queues
+                // again once it resumes
+                let waiting = self.dequeue(peer_id);
+                self.send_to_all(&waiting, &PeerEvent::PeerLeft(peer_id.clone()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops a peer from resuming its session, e.g. when it was told to go away
+    fn forget_resume_token(&mut self, peer_id: &PeerId) {
+        if let Some(token) = self
+            .clients
+            .get_mut(peer_id)
+            .and_then(|peer| peer.resume_token.take())
+        {
+            self.resume_tokens.remove(&token);
+        }
+    }
+
+    /// Everyone else in the peer's room
+    fn room_peers(&mut self, peer_id: &PeerId) -> Vec<PeerId> {
+        match self.room_members_mut(peer_id) {
+            Some(members) => members
+                .iter()
+                .filter(|id| *id != peer_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Kicks everyone in an id room and destroys it. Returns false if there is no such room
+    pub(crate) fn close_room(&mut self, code: &str, reason: &str) -> bool {
+        let room = match self.id_rooms.remove(code) {
+            Some(room) => room,
+            None => return false,
+        };
+        info!("Room {:?} closed", code);
+        for peer_id in &room.lobby.peers {
+            self.kick(peer_id, reason);
+        }
+        self.update_metrics();
+        true
+    }
+
+    fn shutdown_event(&self) -> PeerEvent {
+        PeerEvent::ServerShutdown {
+            reconnect_after_ms: self.config.shutdown.reconnect_after.as_millis() as u64,
+        }
+    }
+
+    /// Stops accepting new peers and tells everyone connected to reconnect later. Peers
+    /// stay connected, so handshakes that are going on can finish
+    pub(crate) fn shutdown(&mut self) {
+        self.shutting_down = true;
+        let peers: Vec<PeerId> = self.clients.keys().cloned().collect();
+        self.send_to_all(&peers, &self.shutdown_event());
+    }
+
+    /// Closes the connection of every peer still there once the drain period is over
+    pub(crate) fn close_connections(&mut self) {
+        let peers: Vec<PeerId> = self.clients.keys().cloned().collect();
+        for peer_id in &peers {
+            self.try_send(peer_id, Message::close());
+        }
+    }
+
+    /// Destroys expired id rooms, disconnecting any peers still in them
+    fn reap_rooms(&mut self) {
+        // ips that haven't created rooms in a while start over anyway
+        self.room_creations.retain(|_, bucket| !bucket.is_full());
+
+        let now = Instant::now();
+        let lifetime = self.config.rooms;
+        let expired: Vec<String> = self
+            .id_rooms
+            .iter()
+            .filter(|(_, room)| room.is_expired(now, &lifetime))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+
+        for room_id in expired {
+            let room = self.id_rooms.remove(&room_id).unwrap();
+            info!("Room {:?} destroyed", room_id);
+
+            let peers: Vec<PeerId> = room.lobby.peers.into_iter().collect();
+            self.send_to_all(
+                &peers,
+                &PeerEvent::Error {
+                    code: ErrorCode::RoomExpired,
+                    message: format!("room {} expired", room_id),
+                },
+            );
+            for peer_id in &peers {
+                self.try_send(peer_id, Message::close());
+                self.forget_resume_token(peer_id);
+            }
+        }
+        self.update_metrics();
+    }
+
+    fn update_metrics(&self) {
+        self.metrics.connected_peers.set(self.clients.len() as i64);
+        self.metrics.id_rooms.set(self.id_rooms.len() as i64);
+        // queues come and go, only report the ones that exist now
+        self.metrics.waiting_peers.reset();
+        for ((version, num_players), peers) in self.next_rooms.iter().chain(&self.rated_queues) {
+            self.metrics
+                .waiting_peers
+                .with_label_values(&[
+                    &num_players.to_string(),
+                    version.as_deref().unwrap_or_default(),
+                ])
+                .add(peers.len() as i64);
+        }
+    }
+
+    fn try_send(&self, id: &PeerId, message: Message) {
+        if let Some(sender) = self.peer_sender(id) {
+            if let Err(e) = sender.send(Ok(message)) {
+                error!("Error sending message {:?}", e);
+            }
+        }
+    }
+
+    /// The sender of a peer, `None` while it is not connected
+    fn peer_sender(&self, id: &PeerId) -> Option<&PeerSender> {
+        match self.clients.get(id) {
+            Some(peer) => peer.sender.as_ref(),
+            None => {
+                error!("Unknown peer {:?}", id);
+                None
+            }
+        }
+    }
+}
+
+/// Resolves once every peer disconnected, e.g. after [`State::shutdown`]
+pub(crate) async fn all_peers_left(state: Arc<Mutex<State>>) {
+    while !state.lock().await.clients.is_empty() {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Periodically destroys expired id rooms
+pub(crate) fn spawn_room_reaper(state: Arc<Mutex<State>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            state.lock().await.reap_rooms();
+        }
+    })
+}
+
+/// Periodically looks for matches in the rated queues, as rating windows widen
+pub(crate) fn spawn_matchmaker(state: Arc<Mutex<State>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            state.lock().await.matchmake();
+        }
+    })
+}
+
+fn parse_room_request(id: String, options: RoomOptions) -> RoomRequest {
+    let room = match id.strip_prefix("next_").and_then(|n| n.parse().ok()) {
+        Some(num_players) => RequestedRoom::Next(num_players),
+        None => RequestedRoom::Id(id),
+    };
+    RoomRequest { room, options }
+}
+
+pub(crate) fn ws_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::ws()
+        .and(warp::any())
+        .and(
+            warp::path::param()
+                .and(warp::query::<RoomOptions>())
+                .map(parse_room_request),
+        )
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(client_addr())
+        .and(with_state(state.clone()))
+        .and_then(ws_handler)
+}
+
+pub(crate) fn with_state(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = (Arc<Mutex<State>>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+pub(crate) async fn ws_handler(
+    ws: warp::ws::Ws,
+    room_request: RoomRequest,
+    protocols: Option<String>,
+    client: ClientAddr,
+    state: Arc<Mutex<State>>,
+) -> std::result::Result<Box<dyn Reply>, Rejection> {
+    let protocol_token = protocols.as_deref().and_then(auth::token_from_protocols);
+    let encoding = protocols
+        .as_deref()
+        .map_or(Encoding::Json, Encoding::from_protocols);
+    if let Some(version) = &room_request.options.version {
+        if !valid_version(version) {
+            warn!("Rejected invalid version {:?}", version);
+            return Ok(Box::new(StatusCode::BAD_REQUEST));
+        }
+    }
+    let (ip, limits, player) = {
+        let state = state.lock().await;
+        if state.shutting_down {
+            return Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        let player = match &state.config.auth.token_secret {
+            Some(secret) => {
+                let token = room_request
+                    .options
+                    .token
+                    .as_deref()
+                    .or(protocol_token.map(|(_, token)| token));
+                let claims = token
+                    .ok_or(TokenError::Malformed)
+                    .and_then(|token| auth::verify(secret, token, auth::unix_now()));
+                match claims {
+                    Ok(claims) if claims.room == room_request.room.path() => Some(claims.player),
+                    Ok(claims) => {
+                        warn!(
+                            "Token for {:?} used to join {:?}",
+                            claims.room, room_request.room
+                        );
+                        return Ok(Box::new(StatusCode::UNAUTHORIZED));
+                    }
+                    Err(e) => {
+                        warn!("Rejected join token for {:?}: {}", room_request.room, e);
+                        return Ok(Box::new(StatusCode::UNAUTHORIZED));
+                    }
+                }
+            }
+            None => None,
+        };
+        let limits = state.config.rate_limits;
+        let ip = client.ip(limits.trust_forwarded_for);
+        if let Some(ip) = ip {
+            if state.connections_from(ip) >= limits.max_connections_per_ip {
+                warn!("Too many connections from {}", ip);
+                return Ok(Box::new(StatusCode::TOO_MANY_REQUESTS));
+            }
+        }
+        (ip, limits, player)
+    };
+
+    // Messages a bit over the limit are dropped with an error in handle_ws,
+    // anything bigger is refused by the websocket itself
+    let max_size = limits.max_message_size.saturating_mul(2);
+    let ws = ws.max_message_size(max_size).max_frame_size(max_size);
+    let reply = ws.on_upgrade(move |websocket| {
+        handle_ws(websocket, state, room_request, ip, player, encoding)
+    });
+    // Clients fail the handshake unless one of their requested protocols is accepted
+    let accepted_protocol = match encoding {
+        Encoding::MessagePack => Some(MSGPACK_PROTOCOL),
+        Encoding::Json => protocol_token.map(|(protocol, _)| protocol),
+    };
+    Ok(match accepted_protocol {
+        Some(protocol) => Box::new(warp::reply::with_header(
+            reply,
+            "sec-websocket-protocol",
+            protocol,
+        )),
+        None => Box::new(reply),
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
+enum RequestError {
+    #[error("Warp error")]
+    WarpError(#[from] warp::Error),
+    #[error("Text error")]
+    TextError,
+    #[error("Json error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("MessagePack error")]
+    MessagePackError(#[from] rmp_serde::decode::Error),
+}
+
+impl From<DecodeError> for RequestError {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::FrameType => RequestError::TextError,
+            DecodeError::Json(e) => RequestError::JsonError(e),
+            DecodeError::MessagePack(e) => RequestError::MessagePackError(e),
+        }
+    }
+}
+
+impl RequestError {
+    /// Name of the variant, used as metrics label
+    fn name(&self) -> &'static str {
+        match self {
+            RequestError::WarpError(_) => "WarpError",
+            RequestError::TextError => "TextError",
+            RequestError::JsonError(_) => "JsonError",
+            RequestError::MessagePackError(_) => "MessagePackError",
+        }
+    }
+}
+
+fn parse_request(
+    request: Result<Message, Error>,
+    encoding: Encoding,
+) -> Result<PeerRequest, RequestError> {
+    let request = request?;
+
+    match encoding.decode(&request) {
+        Err(DecodeError::FrameType) => {
+            warn!(
+                "Got {:?} request in the wrong frame: {:?}",
+                encoding, request
+            );
+            Err(RequestError::TextError)
+        }
+        decoded => Ok(decoded?),
+    }
+}
+
+/// Waits for a legacy client to send the id it picked for itself. `None` if it sends
+/// anything else, or nothing within `timeout`
+async fn receive_uuid(
+    ws_receiver: &mut SplitStream<WebSocket>,
+    encoding: Encoding,
+    timeout: Duration,
+) -> Option<PeerId> {
+    let uuid = async {
+        while let Some(request) = ws_receiver.next().await {
+            match &request {
+                // answered by warp already
+                Ok(message) if message.is_ping() || message.is_pong() => continue,
+                Ok(message) if message.is_close() => return None,
+                _ => {}
+            }
+            return match parse_request(request, encoding) {
+                Ok(PeerRequest::Uuid(id)) => Some(id),
+                request => {
+                    warn!("Expected a uuid from a legacy client, got {:?}", request);
+                    None
+                }
+            };
+        }
+        None
+    };
+    match time::timeout(timeout, uuid).await {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            warn!("Legacy client sent no uuid in time");
+            None
+        }
+    }
+}
+
+/// Forwards messages to the websocket
+fn spawn_sender_task(
+    sender: SplitSink<WebSocket, Message>,
+    messages: Arc<MessageCounts>,
+    encoding: Encoding,
+    legacy: bool,
+) -> PeerSender {
+    let (client_sender, receiver) = mpsc::unbounded_channel();
+    let messages_sent =
+        UnboundedReceiverStream::new(receiver).inspect(move |message: &Result<Message, Error>| {
+            if matches!(message, Ok(message) if message.is_text() || message.is_binary()) {
+                messages.sent.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    tokio::task::spawn(messages_sent.forward(sender));
+    PeerSender {
+        messages: client_sender,
+        encoding,
+        legacy,
+    }
+}
+
+fn send_error(sender: &PeerSender, code: ErrorCode, message: &str) {
+    send_event(
+        sender,
+        &PeerEvent::Error {
+            code,
+            message: message.to_string(),
+        },
+    );
+}
+
+fn send_event(sender: &PeerSender, event: &PeerEvent) {
+    if !sender.understands(event) {
+        return;
+    }
+    if let Err(e) = sender.send(Ok(sender.encoding.encode(event))) {
+        error!("Error sending message {:?}", e);
+    }
+}
+
+async fn handle_ws(
+    websocket: WebSocket,
+    state: Arc<Mutex<State>>,
+    room_request: RoomRequest,
+    ip: Option<IpAddr>,
+    player: Option<String>,
+    encoding: Encoding,
+) {
+    let (ws_sender, mut ws_receiver) = websocket.split();
+    let messages = Arc::new(MessageCounts::default());
+    let (kick, mut kicked) = oneshot::channel();
+    let legacy = room_request.options.legacy();
+    let sender = spawn_sender_task(ws_sender, messages.clone(), encoding, legacy);
+
+    let mut peer_uuid = uuid::Uuid::new_v4().to_string();
+    if legacy {
+        // only join once the client picked its id, so nobody sees it under another one
+        let idle_timeout = state.lock().await.config.keepalive.idle_timeout;
+        match receive_uuid(&mut ws_receiver, encoding, idle_timeout).await {
+            Some(id) => peer_uuid = id,
+            None => {
+                let _ = sender.send(Ok(Message::close()));
+                return;
+            }
+        }
+    }
+    // Counts how often the peer resumed its session, so an old connection can tell that
+    // the peer moved on to a new one
+    let mut connection = 0;
+    let mut departure = Departure::Left;
+
+    {
+        let mut state = state.lock().await;
+        // upgraded just before the shutdown started
+        if state.shutting_down {
+            send_event(&sender, &state.shutdown_event());
+            let _ = sender.send(Ok(Message::close()));
+            return;
+        }
+        if let Some(ip) = ip {
+            // checked before the upgrade already, but others may have connected since
+            if !state.connect_ip(ip) {
+                warn!("Too many connections from {}", ip);
+                send_error(
+                    &sender,
+                    ErrorCode::TooManyConnections,
+                    "too many connections from your address",
+                );
+                let _ = sender.send(Ok(Message::close()));
+                return;
+            }
+        }
+        if legacy && state.clients.contains_key(&peer_uuid) {
+            error!("client tried to use a uuid that is taken: {:?}", peer_uuid);
+            let _ = sender.send(Ok(Message::close()));
+            return;
+        }
+        let resumed = room_request
+            .options
+            .resume
+            .as_ref()
+            .and_then(|token| state.resumable_peer(token));
+        match resumed {
+            Some(id) => {
+                connection = state.resume_peer(&id, sender.clone(), kick, messages.clone(), ip);
+                peer_uuid = id;
+                info!("Peer {:?} resumed its session", peer_uuid);
+                send_event(&sender, &PeerEvent::IdAssigned(peer_uuid.clone()));
+                match state.requeue(&peer_uuid) {
+                    // it lost its place in the queue while it was gone
+                    Some(peers) => announce_peer(&mut state, &sender, &peer_uuid, &peers),
+                    None => {
+                        if let Some(lobby) = state.lobby(&peer_uuid) {
+                            send_room_snapshot(&sender, lobby);
+                        }
+                        let others = state.room_peers(&peer_uuid);
+                        let event = PeerEvent::PeerReconnected(peer_uuid.clone());
+                        state.send_to_all(&others, &event);
+                    }
+                }
+            }
+            // unknown or expired tokens join as a new peer
+            None => {
+                let resume_token = (!state.config.keepalive.resume_grace.is_zero())
+                    .then(|| uuid::Uuid::new_v4().to_string());
+                let peer = Peer {
+                    uuid: peer_uuid.clone(),
+                    sender: Some(sender.clone()),
+                    room: room_request.room.clone(),
+                    matched_room: None,
+                    version: room_request.options.version.clone(),
+                    player,
+                    // spectators don't need a match
+                    rating: room_request
+                        .options
+                        .rating
+                        .filter(|_| room_request.options.role == Role::Player),
+                    max_wait: room_request.options.max_wait,
+                    min_players: room_request.options.min_players.map(NonZeroUsize::get),
+                    spectator: room_request.options.role == Role::Spectator,
+                    ip,
+                    connected_at: Instant::now(),
+                    messages: messages.clone(),
+                    kick: Some(kick),
+                    resume_token,
+                    connection: 0,
+                };
+                if !join_room(&mut state, &sender, &room_request, peer) {
+                    return;
+                }
+            }
+        }
+    }
+
+    let (metrics, keepalive, limits) = {
+        let state = state.lock().await;
+        (
+            state.metrics.clone(),
+            state.config.keepalive,
+            state.config.rate_limits,
+        )
+    };
+    let mut bucket = TokenBucket::new(limits.messages_per_second, limits.burst);
+    let mut ping_interval = time::interval_at(
+        Instant::now() + keepalive.ping_interval,
+        keepalive.ping_interval,
+    );
+    let mut pong_deadline: Option<Instant> = None;
+
+    loop {
+        let request = select! {
+            request = ws_receiver.next() => match request {
+                Some(request) => request,
+                None => {
+                    departure = Departure::Lost;
+                    break;
+                }
+            },
+            _ = ping_interval.tick() => {
+                if pong_deadline.is_none() {
+                    pong_deadline = Some(Instant::now() + keepalive.pong_timeout);
+                }
+                if let Err(e) = sender.send(Ok(Message::ping(vec![]))) {
+                    error!("Error sending ping {:?}", e);
+                }
+                continue;
+            }
+            Ok(disconnect) = &mut kicked => {
+                match disconnect {
+                    Disconnect::Kicked(reason) => {
+                        info!("Peer {:?} was kicked: {}", peer_uuid, reason);
+                        send_event(&sender, &PeerEvent::Kicked { reason });
+                    }
+                    Disconnect::Resumed => {
+                        info!("Peer {:?} continues on a new connection", peer_uuid);
+                    }
+                }
+                let _ = sender.send(Ok(Message::close()));
+                break;
+            }
+            _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                warn!("Peer {:?} timed out", peer_uuid);
+                send_error(&sender, ErrorCode::Timeout, "connection timed out");
+                let _ = sender.send(Ok(Message::close()));
+                departure = Departure::Lost;
+                break;
+            }
+        };
+
+        match &request {
+            Ok(message) if message.is_close() => break,
+            Ok(message) if message.is_pong() => {
+                pong_deadline = None;
+                continue;
+            }
+            // answered by warp already
+            Ok(message) if message.is_ping() => continue,
+            _ => {}
+        }
+        messages.received.fetch_add(1, Ordering::Relaxed);
+
+        // Drop messages of peers that send too much, and give up on them if they keep doing so
+        let ip_limit = match ip {
+            Some(ip) => state.lock().await.take_ip_token(ip),
+            None => Limit::Allowed,
+        };
+        match bucket.take().max(ip_limit) {
+            Limit::Allowed => {}
+            Limit::Throttled => {
+                send_error(
+                    &sender,
+                    ErrorCode::Throttled,
+                    "too many messages, slow down",
+                );
+                continue;
+            }
+            Limit::Exceeded => {
+                warn!("Peer {:?} exceeded the rate limit", peer_uuid);
+                send_error(&sender, ErrorCode::RateLimited, "too many messages");
+                let _ = sender.send(Ok(Message::close()));
+                break;
+            }
+        }
+
+        if let Ok(message) = &request {
+            if message.as_bytes().len() > limits.max_message_size {
+                warn!("Peer {:?} sent a message that is too large", peer_uuid);
+                send_error(
+                    &sender,
+                    ErrorCode::MessageTooLarge,
+                    &format!("messages may be at most {} bytes", limits.max_message_size),
+                );
+                continue;
+            }
+        }
+
+        let request = parse_request(request, encoding);
+        if let Err(e) = &request {
+            metrics.parse_errors.with_label_values(&[e.name()]).inc();
+        }
+        let request = match request {
+            Ok(request) => request,
+            Err(RequestError::WarpError(e)) => {
+                error!("Warp error while receiving request: {:?}", e);
+                // Most likely a ConnectionReset or similar.
+                // just give up on this connection.
+                departure = Departure::Lost;
+                break;
+            }
+            Err(RequestError::JsonError(e)) => {
+                error!("Error untangling request: {:?}", e);
+                send_error(&sender, ErrorCode::MalformedRequest, &e.to_string());
+                continue;
+            }
+            Err(RequestError::MessagePackError(e)) => {
+                error!("Error untangling request: {:?}", e);
+                send_error(&sender, ErrorCode::MalformedRequest, &e.to_string());
+                continue;
+            }
+            Err(e) => {
+                error!("Error untangling request: {:?}", e);
+                continue;
+            }
+        };
+
+        info!("{:?} <- {:?}", peer_uuid, request);
+
+        match request {
+            PeerRequest::Uuid(_) => {
+                error!("client set uuid after joining");
+                send_error(
+                    &sender,
+                    ErrorCode::DuplicateUuid,
+                    "the peer has an id already",
+                );
+            }
+            PeerRequest::TransferHost(new_host) => {
+                let mut state = state.lock().await;
+                match state.transfer_host(&peer_uuid, &new_host) {
+                    Ok(members) => {
+                        info!("Peer {:?} made {:?} host", peer_uuid, new_host);
+                        state.send_to_all(&members, &PeerEvent::HostChanged(new_host));
+                    }
+                    Err(HostError::NotHost) => send_error(
+                        &sender,
+                        ErrorCode::NotHost,
+                        "only the host can transfer the host role",
+                    ),
+                    Err(HostError::UnknownPeer) => send_error(
+                        &sender,
+                        ErrorCode::UnknownReceiver,
+                        &format!("no peer with uuid {} in this room", new_host),
+                    ),
+                }
+            }
+            PeerRequest::AssignSeats => {
+                let mut state = state.lock().await;
+                match state.assign_seats(&peer_uuid) {
+                    Ok(seats) => {
+                        info!("Peer {:?} assigned seats {:?}", peer_uuid, seats);
+                        state.send_to_all(&seats, &PeerEvent::Seats(seats.clone()));
+                    }
+                    Err(_) => send_error(
+                        &sender,
+                        ErrorCode::NotHost,
+                        "only the host can assign seats",
+                    ),
+                }
+            }
+            PeerRequest::SetRoomSettings(settings) => {
+                let mut state = state.lock().await;
+                match state.set_room_settings(&peer_uuid, settings.clone()) {
+                    Ok(members) => {
+                        state.send_to_all(&members, &PeerEvent::RoomSettings(settings));
+                    }
+                    Err(_) => send_error(
+                        &sender,
+                        ErrorCode::NotHost,
+                        "only the host can change the room settings",
+                    ),
+                }
+            }
+            PeerRequest::Broadcast(data) => {
+                if data.to_string().len() > limits.max_broadcast_size {
+                    send_error(
+                        &sender,
+                        ErrorCode::MessageTooLarge,
+                        &format!(
+                            "broadcasts may be at most {} bytes",
+                            limits.max_broadcast_size
+                        ),
+                    );
+                    continue;
+                }
+                let mut state = state.lock().await;
+                state.touch_room(&peer_uuid);
+                // peers waiting for a next_N room don't know about each other yet
+                let members = match state.lobby(&peer_uuid) {
+                    Some(lobby) => lobby.peers.iter().cloned().collect(),
+                    None => vec![peer_uuid.clone()],
+                };
+                let event = PeerEvent::Broadcast {
+                    sender: peer_uuid.clone(),
+                    data,
+                };
+                state.send_to_all(&members, &event);
+            }
+            PeerRequest::SetState { key, value } => {
+                let change = StateChange {
+                    peer: Some(peer_uuid.clone()),
+                    key,
+                    value,
+                };
+                set_state(&state, &sender, &peer_uuid, change, &limits).await;
+            }
+            PeerRequest::SetRoomState { key, value } => {
+                let change = StateChange {
+                    peer: None,
+                    key,
+                    value,
+                };
+                set_state(&state, &sender, &peer_uuid, change, &limits).await;
+            }
+            PeerRequest::Ready(ready) => {
+                let mut state = state.lock().await;
+                state.touch_room(&peer_uuid);
+                match state.set_ready(&peer_uuid, ready) {
+                    Ok(Some(seat_order)) => {
+                        info!("Starting game with {:?}", seat_order);
+                        let event = PeerEvent::StartGame {
+                            seat_order: seat_order.clone(),
+                            start_in_ms: state.config.start_delay.as_millis() as u64,
+                        };
+                        // spectators want to know when to start watching as well
+                        let members: Vec<PeerId> = match state.lobby(&peer_uuid) {
+                            Some(lobby) => lobby.peers.iter().cloned().collect(),
+                            None => seat_order,
+                        };
+                        state.send_to_all(&members, &event);
+                    }
+                    Ok(None) => {}
+                    Err(StateError::Spectator) => send_error(
+                        &sender,
+                        ErrorCode::Spectator,
+                        "spectators can't take part in the game",
+                    ),
+                    Err(_) => send_error(
+                        &sender,
+                        ErrorCode::NotInRoom,
+                        "peers can be ready once the room is filled",
+                    ),
+                }
+            }
+            PeerRequest::Signal { receiver, data } => {
+                let event = PeerEvent::Signal {
+                    sender: peer_uuid.clone(),
+                    data,
+                };
+                let mut state = state.lock().await;
+                state.touch_room(&peer_uuid);
+                let receiver_sender = match state
+                    .clients
+                    .get(&receiver)
+                    .and_then(|peer| peer.sender.as_ref())
+                {
+                    Some(receiver_sender) => receiver_sender,
+                    None => {
+                        warn!("client is trying to signal unknown peer {:?}", receiver);
+                        send_error(
+                            &sender,
+                            ErrorCode::UnknownReceiver,
+                            &format!("no peer with uuid {}", receiver),
+                        );
+                        continue;
+                    }
+                };
+                let message = receiver_sender.encoding.encode(&event);
+                if let Err(e) = receiver_sender.send(Ok(message)) {
+                    error!("error sending: {:?}", e);
+                } else {
+                    metrics.signals_relayed.inc();
+                }
+            }
+        }
+    }
+
+    let shared_state = state.clone();
+    let mut state = state.lock().await;
+    if let Some(ip) = ip {
+        state.disconnect_ip(ip);
+    }
+    let resumed_elsewhere = state
+        .clients
+        .get(&peer_uuid)
+        .is_none_or(|peer| peer.connection != connection);
+    if resumed_elsewhere {
+        return;
+    }
+
+    if departure == Departure::Lost && state.suspend_peer(&peer_uuid) {
+        info!("Peer {:?} lost its connection", peer_uuid);
+        let grace = state.config.keepalive.resume_grace;
+        tokio::spawn(async move {
+            time::sleep(grace).await;
+            let mut state = shared_state.lock().await;
+            let suspended = state
+                .clients
+                .get(&peer_uuid)
+                .is_some_and(|peer| peer.sender.is_none() && peer.connection == connection);
+            if suspended {
+                info!("Peer {:?} did not resume its session", peer_uuid);
+                state.leave(&peer_uuid);
+            }
+        });
+        return;
+    }
+
+    info!("Removing peer: {:?}", peer_uuid);
+    state.leave(&peer_uuid);
+}
+
+/// Adds a new peer to the room it asked for and tells everyone concerned.
+/// Returns false if the peer can't join, its connection is closed then
+fn join_room(
+    state: &mut State,
+    sender: &PeerSender,
+    room_request: &RoomRequest,
+    peer: Peer,
+) -> bool {
+    let peer_uuid = peer.uuid.clone();
+    let ip = peer.ip;
+    let resume_token = peer.resume_token.clone();
+    let peers = match state.add_peer(peer, &room_request.options) {
+        Ok(peers) => peers,
+        Err(e) => {
+            warn!("Peer can't join {:?}: {:?}", room_request.room, e);
+            let event = match e {
+                JoinError::RoomFull => PeerEvent::RoomFull,
+                JoinError::VersionMismatch(room_version) => {
+                    PeerEvent::VersionMismatch { room_version }
+                }
+                JoinError::TooManyRooms => PeerEvent::Error {
+                    code: ErrorCode::TooManyRooms,
+                    message: "the server can't create any more rooms".to_string(),
+                },
+                JoinError::InvalidRoom => PeerEvent::Error {
+                    code: ErrorCode::InvalidRoom,
+                    message: format!("{:?} exceeds the server limits", room_request.room),
+                },
+            };
+            send_event(sender, &event);
+            let _ = sender.send(Ok(Message::close()));
+            if let Some(ip) = ip {
+                state.disconnect_ip(ip);
+            }
+            return false;
+        }
+    };
+    send_event(sender, &PeerEvent::IdAssigned(peer_uuid.clone()));
+    if let Some(token) = resume_token {
+        send_event(sender, &PeerEvent::ResumeToken(token));
+    }
+    announce_peer(state, sender, &peer_uuid, &peers);
+    true
+}
+
+/// Tells `peers`, those already in the room or queue a peer joined, about the peer and
+/// the peer about its room
+fn announce_peer(state: &mut State, sender: &PeerSender, peer_uuid: &PeerId, peers: &[PeerId]) {
+    let (spectator, next_room, rated) = match state.clients.get(peer_uuid) {
+        Some(peer) => (
+            peer.spectator,
+            matches!(peer.room, RequestedRoom::Next(_)),
+            peer.rating.is_some(),
+        ),
+        None => return,
+    };
+
+    // Tell the players about this new peer
+    let event = if spectator {
+        PeerEvent::NewSpectator(peer_uuid.clone())
+    } else {
+        PeerEvent::NewPeer(peer_uuid.clone())
+    };
+    state.send_to_all(peers, &event);
+    // and new players about the spectators that are there already
+    if !spectator {
+        for spectator in state.room_spectators(peer_uuid) {
+            send_event(sender, &PeerEvent::NewSpectator(spectator));
+        }
+    }
+
+    if let Some(lobby) = state.lobby(peer_uuid) {
+        if next_room {
+            // the next_N room just filled up and got its first host and seats
+            let members: Vec<PeerId> = lobby.peers.iter().cloned().collect();
+            if let Some(host) = &lobby.host {
+                state.send_to_all(&members, &PeerEvent::HostChanged(host.clone()));
+            }
+            if let Some(seats) = &lobby.seats {
+                state.send_to_all(&members, &PeerEvent::Seats(seats.clone()));
+            }
+        } else {
+            send_room_snapshot(sender, lobby);
+        }
+    }
+    if rated {
+        state.matchmake();
+    }
+}
+
+/// Sends what a peer joining or resuming needs to know about its room
+fn send_room_snapshot(sender: &PeerSender, lobby: &Lobby) {
+    if let Some(host) = &lobby.host {
+        send_event(sender, &PeerEvent::HostChanged(host.clone()));
+    }
+    if let Some(seats) = &lobby.seats {
+        send_event(sender, &PeerEvent::Seats(seats.clone()));
+    }
+    if let Some(settings) = &lobby.settings {
+        send_event(sender, &PeerEvent::RoomSettings(settings.clone()));
+    }
+    if !lobby.state.is_empty() {
+        let event = PeerEvent::RoomState {
+            snapshot: true,
+            changes: lobby.state.snapshot(),
+        };
+        send_event(sender, &event);
+    }
+}
+
+/// Handles `SetState` and `SetRoomState`, passing the change on to everyone in the room
+async fn set_state(
+    state: &Arc<Mutex<State>>,
+    sender: &PeerSender,
+    peer_id: &PeerId,
+    change: StateChange<serde_json::Value>,
+    limits: &RateLimits,
+) {
+    let size = change
+        .value
+        .as_ref()
+        .map_or(0, |value| value.to_string().len());
+    if size > limits.max_broadcast_size {
+        send_error(
+            sender,
+            ErrorCode::MessageTooLarge,
+            &format!(
+                "state values may be at most {} bytes",
+                limits.max_broadcast_size
+            ),
+        );
+        return;
+    }
+    let mut state = state.lock().await;
+    state.touch_room(peer_id);
+    match state.set_state(peer_id, &change) {
+        Ok(members) => {
+            let event = PeerEvent::RoomState {
+                snapshot: false,
+                changes: vec![change],
+            };
+            state.send_to_all(&members, &event);
+        }
+        Err(StateError::NotInRoom) => send_error(
+            sender,
+            ErrorCode::NotInRoom,
+            "the room state is available once the room is filled",
+        ),
+        Err(StateError::NotHost) => send_error(
+            sender,
+            ErrorCode::NotHost,
+            "only the host can change room-wide state",
+        ),
+        Err(StateError::Spectator) => send_error(
+            sender,
+            ErrorCode::Spectator,
+            "spectators can't change the room state",
+        ),
+        Err(StateError::TooManyKeys) => send_error(
+            sender,
+            ErrorCode::TooManyKeys,
+            &format!(
+                "the room state allows at most {} keys per namespace",
+                limits.max_state_keys
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{net::SocketAddr, num::NonZeroUsize, sync::Arc, time::Duration};
+
+    use futures::{lock::Mutex, pin_mut, SinkExt, StreamExt};
+    use matchbox_socket::{CloseReason, Encoding as SocketEncoding, WebRtcSocket};
+    use serde::Deserialize;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::{
+        select,
+        sync::{mpsc, oneshot},
+        time::{self, Instant},
+    };
+    use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+    use warp::{test::WsClient, ws::Message, Filter, Rejection, Reply};
+
+    use crate::config::{Config, Keepalive, Matchmaking, RateLimits, RoomLifetime, Shutdown};
+    use crate::encoding::Encoding;
+    use crate::signaling::{
+        all_peers_left, parse_room_request, spawn_matchmaker, spawn_room_reaper, ws_filter,
+        ErrorCode, Peer, PeerEvent, PeerId, PeerRequest, PeerSender, RequestedRoom, RoomOptions,
+        State, StateChange,
+    };
+
+    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        super::ws_filter(Default::default())
+    }
+
+    #[tokio::test]
+    async fn ws_connect() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        // let req = warp::test::ws().path("/echo?protocol=2");
+        warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+    }
+
+    #[tokio::test]
+    async fn id_assigned() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+        let id_b = recv_id_assigned(&mut client_b).await;
+
+        assert_ne!(id_a, id_b);
+    }
+
+    #[tokio::test]
+    async fn new_peer() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        // let req = warp::test::ws().path("/echo?protocol=2");
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_b = recv_id_assigned(&mut client_b).await;
+        assert_eq!(recv_host_changed(&mut client_b).await, id_a);
+
+        let a_msg = client_a.recv().await;
+        let new_peer_event: PeerEvent =
+            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
+
+        assert_eq!(new_peer_event, PeerEvent::NewPeer(id_b));
+    }
+
+    #[tokio::test]
+    async fn signal() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        // let req = warp::test::ws().path("/echo?protocol=2");
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+
+        let a_msg = client_a.recv().await;
+        let new_peer_event: PeerEvent =
+            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
+
+        let peer_uuid = match new_peer_event {
+            PeerEvent::NewPeer(peer) => peer,
+            _ => panic!("unexpected event"),
+        };
+
+        client_a
+            .send(Message::text(format!(
+                "{{\"Signal\": {{\"receiver\": \"{}\", \"data\": \"123\" }}}}",
+                peer_uuid
+            )))
+            .await;
+
+        let b_msg = client_b.recv().await;
+        let signal_event: PeerEvent =
+            serde_json::from_str(b_msg.unwrap().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            signal_event,
+            PeerEvent::Signal {
+                data: serde_json::Value::String("123".to_string()),
+                sender: id_a,
+            }
+        );
+    }
+
+    async fn recv_msgpack_event(client: &mut WsClient) -> PeerEvent {
+        let message = client.recv().await.unwrap();
+        assert!(message.is_binary());
+        rmp_serde::from_slice(message.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn msgpack() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .header("sec-websocket-protocol", "msgpack")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_b = match recv_msgpack_event(&mut client_b).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected assigned id, got {:?}", event),
+        };
+        assert!(matches!(
+            recv_msgpack_event(&mut client_b).await,
+            PeerEvent::ResumeToken(_)
+        ));
+        assert_eq!(
+            recv_msgpack_event(&mut client_b).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        // json and msgpack peers can signal each other
+        let request = PeerRequest::Signal {
+            receiver: id_a.clone(),
+            data: serde_json::json!({ "Offer": "sdp" }),
+        };
+        client_b
+            .send(Message::binary(rmp_serde::to_vec_named(&request).unwrap()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Signal {
+                sender: id_b.clone(),
+                data: serde_json::json!({ "Offer": "sdp" }),
+            }
+        );
+
+        client_a
+            .send_text(format!(
+                r#"{{"Signal": {{"receiver": "{}", "data": {{"Answer": "sdp"}}}}}}"#,
+                id_b
+            ))
+            .await;
+        assert_eq!(
+            recv_msgpack_event(&mut client_b).await,
+            PeerEvent::Signal {
+                sender: id_a,
+                data: serde_json::json!({ "Answer": "sdp" }),
+            }
+        );
+
+        client_b.send(Message::binary(vec![0xc1])).await;
+        assert!(matches!(
+            recv_msgpack_event(&mut client_b).await,
+            PeerEvent::Error {
+                code: ErrorCode::MalformedRequest,
+                ..
+            }
+        ));
+    }
+
+    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
+        let message = client.recv().await;
+        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
+    }
+
+    async fn recv_id_assigned(client: &mut WsClient) -> PeerId {
+        recv_session(client).await.0
+    }
+
+    /// Receives the assigned id and the resume token that comes with it
+    async fn recv_session(client: &mut WsClient) -> (PeerId, String) {
+        let id = match recv_peer_event(client).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected assigned id, got {:?}", event),
+        };
+        match recv_peer_event(client).await {
+            PeerEvent::ResumeToken(token) => (id, token),
+            event => panic!("expected resume token, got {:?}", event),
+        }
+    }
+
+    async fn recv_host_changed(client: &mut WsClient) -> PeerId {
+        match recv_peer_event(client).await {
+            PeerEvent::HostChanged(id) => id,
+            event => panic!("expected host change, got {:?}", event),
+        }
+    }
+
+    async fn recv_seats(client: &mut WsClient) -> Vec<PeerId> {
+        match recv_peer_event(client).await {
+            PeerEvent::Seats(seats) => seats,
+            event => panic!("expected seats, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn match_pairs() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_b = recv_id_assigned(&mut client_b).await;
+
+        let mut client_c = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+
+        let id_c = recv_id_assigned(&mut client_c).await;
+
+        let mut client_d = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
             // .handshake(ws_echo())
             .await
             .expect("handshake");
 
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+        let id_d = recv_id_assigned(&mut client_d).await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
+        let new_peer_b = recv_peer_event(&mut client_a).await;
+        let new_peer_d = recv_peer_event(&mut client_c).await;
+
+        assert_eq!(new_peer_b, PeerEvent::NewPeer(id_b.clone()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer(id_d.clone()));
+
+        // whoever waited longest hosts the match, and gets the first seat
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+        assert_eq!(recv_host_changed(&mut client_b).await, id_a);
+        assert_eq!(recv_host_changed(&mut client_c).await, id_c);
+        assert_eq!(recv_host_changed(&mut client_d).await, id_c);
+        let seats_ab = vec![id_a.clone(), id_b.clone()];
+        assert_eq!(recv_seats(&mut client_a).await, seats_ab);
+        assert_eq!(recv_seats(&mut client_b).await, seats_ab);
+        let seats_cd = vec![id_c.clone(), id_d.clone()];
+        assert_eq!(recv_seats(&mut client_c).await, seats_cd);
+        assert_eq!(recv_seats(&mut client_d).await, seats_cd);
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = client_c.recv() => panic!("unexpected message"),
+            _ = client_d.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn match_ratings() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut clients = vec![];
+        let mut ids = vec![];
+        for rating in [1000, 1500, 1050, 1480] {
+            let mut client = warp::test::ws()
+                .path(&format!("/next_2?rating={}&protocol=2", rating))
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            ids.push(recv_id_assigned(&mut client).await);
+            clients.push(client);
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let (id_a, id_b, id_c, id_d) = (&ids[0], &ids[1], &ids[2], &ids[3]);
+        let [client_a, client_b, client_c, client_d] = &mut clients[..] else {
+            unreachable!()
+        };
+
+        // Clients are matched by rating, i.e. a + c and b + d
+        assert_eq!(
+            recv_peer_event(client_a).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+        assert_eq!(
+            recv_peer_event(client_b).await,
+            PeerEvent::NewPeer(id_d.clone())
+        );
+        assert_eq!(&recv_host_changed(client_a).await, id_a);
+        assert_eq!(&recv_host_changed(client_c).await, id_a);
+        assert_eq!(&recv_host_changed(client_b).await, id_b);
+        assert_eq!(&recv_host_changed(client_d).await, id_b);
+        let seats_ac = vec![id_a.clone(), id_c.clone()];
+        assert_eq!(recv_seats(client_a).await, seats_ac);
+        assert_eq!(recv_seats(client_c).await, seats_ac);
+        let seats_bd = vec![id_b.clone(), id_d.clone()];
+        assert_eq!(recv_seats(client_b).await, seats_bd);
+        assert_eq!(recv_seats(client_d).await, seats_bd);
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = client_c.recv() => panic!("unexpected message"),
+            _ = client_d.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn matchmaking_timeout() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(State::new(Config {
+            matchmaking: Matchmaking {
+                max_wait: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        })));
+        spawn_matchmaker(state.clone(), Duration::from_millis(50));
+        let api = ws_filter(state);
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?rating=1000&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_a).await;
+        let mut client_b = warp::test::ws()
+            .path("/next_2?rating=3000&protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_b).await;
+
+        // nobody is close enough in rating, until the window has grown by 2000
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(recv_peer_event(client).await, PeerEvent::MatchmakingTimeout);
+            assert!(client.recv_closed().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn finish_room_early() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(State::default()));
+        spawn_matchmaker(state.clone(), Duration::from_millis(50));
+        let api = ws_filter(state);
+
+        let mut clients = vec![];
+        let mut ids = vec![];
+        for path in [
+            "/next_4?max_wait=300ms&min_players=2&protocol=2",
+            "/next_4?min_players=2&protocol=2",
+            "/next_4?protocol=2",
+        ] {
+            let mut client = warp::test::ws()
+                .path(path)
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            ids.push(recv_id_assigned(&mut client).await);
+            clients.push(client);
+        }
+        let (id_a, id_b, id_c) = (&ids[0], &ids[1], &ids[2]);
+        let [client_a, client_b, client_c] = &mut clients[..] else {
+            unreachable!()
+        };
+        assert_eq!(
+            recv_peer_event(client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+        assert_eq!(
+            recv_peer_event(client_a).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+        assert_eq!(
+            recv_peer_event(client_b).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+
+        // c wants a full room, so a and b play on their own once a waited long enough
+        for client in [&mut *client_a, &mut *client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::PeerLeft(id_c.clone())
+            );
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::MatchFormed {
+                    peers: vec![id_a.clone(), id_b.clone()]
+                }
+            );
+            assert_eq!(&recv_host_changed(client).await, id_a);
+            assert_eq!(recv_seats(client).await, vec![id_a.clone(), id_b.clone()]);
+        }
+        assert_eq!(
+            recv_peer_event(client_c).await,
+            PeerEvent::PeerLeft(id_a.clone())
+        );
+        assert_eq!(
+            recv_peer_event(client_c).await,
+            PeerEvent::PeerLeft(id_b.clone())
+        );
+
+        // d wants a full room as well, and gives up
+        let mut client_d = warp::test::ws()
+            .path("/next_4?max_wait=100ms&protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_d = recv_id_assigned(&mut client_d).await;
+        assert_eq!(
+            recv_peer_event(&mut client_d).await,
+            PeerEvent::MatchmakingTimeout
+        );
+        assert!(client_d.recv_closed().await.is_ok());
+        assert_eq!(
+            recv_peer_event(client_c).await,
+            PeerEvent::NewPeer(id_d.clone())
+        );
+        assert_eq!(recv_peer_event(client_c).await, PeerEvent::PeerLeft(id_d));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = client_c.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    /// The only events clients without `?protocol=2` can parse
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum LegacyEvent {
+        NewPeer(PeerId),
+        Signal {
+            sender: PeerId,
+            data: serde_json::Value,
+        },
+    }
+
+    async fn recv_legacy_event(client: &mut WsClient) -> LegacyEvent {
+        let message = client.recv().await.unwrap();
+        let message = message.to_str().unwrap();
+        serde_json::from_str(message)
+            .unwrap_or_else(|_| panic!("legacy client can't parse {}", message))
+    }
+
+    #[tokio::test]
+    async fn legacy_uuid() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        // Legacy clients join under the id they pick, and only once they picked it
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        client_b
+            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer("uuid-b".to_string())
+        );
+
+        client_a
+            .send_text(r#"{"Signal": {"receiver": "uuid-b", "data": "123"}}"#)
+            .await;
+        assert_eq!(
+            recv_legacy_event(&mut client_b).await,
+            LegacyEvent::Signal {
+                sender: id_a.clone(),
+                data: serde_json::Value::String("123".to_string()),
+            }
+        );
+        client_b
+            .send(Message::text(format!(
+                r#"{{"Signal": {{"receiver": "{}", "data": "456"}}}}"#,
+                id_a
+            )))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Signal {
+                sender: "uuid-b".to_string(),
+                data: serde_json::Value::String("456".to_string()),
+            }
+        );
+
+        // b doesn't hear about a leaving or b becoming host, only about the next peer
+        drop(client_a);
+        let mut client_c = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client_c
+            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
+            .await;
+        assert_eq!(
+            recv_legacy_event(&mut client_b).await,
+            LegacyEvent::NewPeer("uuid-c".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_left() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        let id_b = recv_id_assigned(&mut client_b).await;
+        assert_eq!(recv_host_changed(&mut client_b).await, id_a);
+
+        let new_peer_b = recv_peer_event(&mut client_a).await;
+        assert_eq!(new_peer_b, PeerEvent::NewPeer(id_b.clone()));
+
+        drop(client_b);
+
+        let peer_left_b = recv_peer_event(&mut client_a).await;
+        assert_eq!(peer_left_b, PeerEvent::PeerLeft(id_b));
+    }
+
+    #[tokio::test]
+    async fn peer_left_matched_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let id_a = recv_id_assigned(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        let id_b = recv_id_assigned(&mut client_b).await;
+
+        let new_peer_b = recv_peer_event(&mut client_a).await;
+        assert_eq!(new_peer_b, PeerEvent::NewPeer(id_b.clone()));
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+        assert_eq!(recv_host_changed(&mut client_b).await, id_a);
+        recv_seats(&mut client_b).await;
+
+        // The room is full at this point, but b is still a member of it
+        drop(client_a);
+
+        let peer_left_a = recv_peer_event(&mut client_b).await;
+        assert_eq!(peer_left_a, PeerEvent::PeerLeft(id_a));
+        assert_eq!(recv_host_changed(&mut client_b).await, id_b);
+    }
+
+    #[tokio::test]
+    async fn host() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+
+        client_a
+            .send(Message::text(r#"{"SetRoomSettings": {"map": 1}}"#))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomSettings(serde_json::json!({"map": 1}))
+        );
+
+        // newcomers learn about the host and the settings
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        assert_eq!(recv_host_changed(&mut client_b).await, id_a);
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::RoomSettings(serde_json::json!({"map": 1}))
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_c = recv_id_assigned(&mut client_c).await;
+        recv_host_changed(&mut client_c).await;
+        recv_peer_event(&mut client_c).await;
+        recv_peer_event(&mut client_a).await;
+        recv_peer_event(&mut client_b).await;
+
+        // only the host decides
+        client_b
+            .send(Message::text(r#"{"SetRoomSettings": {"map": 2}}"#))
+            .await;
+        assert_eq!(recv_error_code(&mut client_b).await, ErrorCode::NotHost);
+        client_b
+            .send(Message::text(format!("{{\"TransferHost\": \"{}\"}}", id_b)))
+            .await;
+        assert_eq!(recv_error_code(&mut client_b).await, ErrorCode::NotHost);
+        client_a
+            .send(Message::text(r#"{"TransferHost": "nobody"}"#))
+            .await;
+        assert_eq!(
+            recv_error_code(&mut client_a).await,
+            ErrorCode::UnknownReceiver
+        );
+
+        client_a
+            .send(Message::text(format!("{{\"TransferHost\": \"{}\"}}", id_c)))
+            .await;
+        for client in [&mut client_a, &mut client_b, &mut client_c] {
+            assert_eq!(recv_host_changed(client).await, id_c);
+        }
+
+        // the host leaves, the longest-connected peer takes over
+        drop(client_c);
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::PeerLeft(id_c.clone())
+            );
+            assert_eq!(recv_host_changed(client).await, id_a);
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast() {
+        let _ = pretty_env_logger::try_init();
+        let api = ws_filter(Arc::new(Mutex::new(State::new(Config {
+            rate_limits: RateLimits {
+                max_broadcast_size: 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        }))));
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+        recv_peer_event(&mut client_a).await;
+
+        client_b.send(Message::text(r#"{"Broadcast": "hi"}"#)).await;
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::Broadcast {
+                    sender: id_b.clone(),
+                    data: serde_json::json!("hi"),
+                }
+            );
+        }
+
+        client_b
+            .send(Message::text(format!(
+                r#"{{"Broadcast": "{}"}}"#,
+                "x".repeat(20)
+            )))
+            .await;
+        assert_eq!(
+            recv_error_code(&mut client_b).await,
+            ErrorCode::MessageTooLarge
+        );
+
+        // nobody else hears a peer waiting for its next_N room
+        let mut client_c = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_c = recv_id_assigned(&mut client_c).await;
+        client_c
+            .send(Message::text(r#"{"Broadcast": "anyone?"}"#))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::Broadcast {
+                sender: id_c,
+                data: serde_json::json!("anyone?"),
+            }
+        );
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn room_state() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+        recv_peer_event(&mut client_a).await;
+
+        let map = StateChange {
+            peer: None,
+            key: "map".to_string(),
+            value: Some(serde_json::json!("forest")),
+        };
+        let color = StateChange {
+            peer: Some(id_b.clone()),
+            key: "color".to_string(),
+            value: Some(serde_json::json!("red")),
+        };
+
+        client_b
+            .send(Message::text(
+                r#"{"SetRoomState": {"key": "map", "value": "desert"}}"#,
+            ))
+            .await;
+        assert_eq!(recv_error_code(&mut client_b).await, ErrorCode::NotHost);
+
+        client_a
+            .send(Message::text(
+                r#"{"SetRoomState": {"key": "map", "value": "forest"}}"#,
+            ))
+            .await;
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::RoomState {
+                    snapshot: false,
+                    changes: vec![map.clone()],
+                }
+            );
+        }
+        client_b
+            .send(Message::text(
+                r#"{"SetState": {"key": "color", "value": "red"}}"#,
+            ))
+            .await;
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::RoomState {
+                    snapshot: false,
+                    changes: vec![color.clone()],
+                }
+            );
+        }
+
+        // newcomers get everything at once
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_c).await;
+        recv_host_changed(&mut client_c).await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::RoomState {
+                snapshot: true,
+                changes: vec![map, color.clone()],
+            }
+        );
+        recv_peer_event(&mut client_a).await;
+
+        // the state of peers that left is gone
+        drop(client_b);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_b)
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::RoomState {
+                snapshot: false,
+                changes: vec![StateChange {
+                    value: None,
+                    ..color
+                }],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn start_game() {
+        let _ = pretty_env_logger::try_init();
+        let api = ws_filter(Arc::new(Mutex::new(State::new(Config {
+            start_delay: Duration::from_secs(5),
+            ..Default::default()
+        }))));
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+        recv_peer_event(&mut client_a).await;
+
+        // b changes its mind before a is ready
+        client_b.send(Message::text(r#"{"Ready": true}"#)).await;
+        client_b.send(Message::text(r#"{"Ready": false}"#)).await;
+        time::sleep(Duration::from_millis(50)).await;
+        client_a.send(Message::text(r#"{"Ready": true}"#)).await;
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+
+        client_b.send(Message::text(r#"{"Ready": true}"#)).await;
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::StartGame {
+                    seat_order: vec![id_a.clone(), id_b.clone()],
+                    start_in_ms: 5000,
+                }
+            );
+        }
+
+        // peers waiting for a next_N room can't be ready yet
+        let mut client_c = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_c).await;
+        client_c.send(Message::text(r#"{"Ready": true}"#)).await;
+        assert_eq!(recv_error_code(&mut client_c).await, ErrorCode::NotInRoom);
+    }
+
+    #[tokio::test]
+    async fn assign_seats() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+        recv_peer_event(&mut client_a).await;
+
+        client_b.send(Message::text(r#""AssignSeats""#)).await;
+        assert_eq!(recv_error_code(&mut client_b).await, ErrorCode::NotHost);
+
+        client_a.send(Message::text(r#""AssignSeats""#)).await;
+        let seats = vec![id_a.clone(), id_b.clone()];
+        assert_eq!(recv_seats(&mut client_a).await, seats);
+        assert_eq!(recv_seats(&mut client_b).await, seats);
+
+        // late peers learn the seats, and sit after everyone seated still there
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_c = recv_id_assigned(&mut client_c).await;
+        recv_host_changed(&mut client_c).await;
+        assert_eq!(recv_seats(&mut client_c).await, seats);
+        recv_peer_event(&mut client_a).await;
+
+        drop(client_b);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_b)
+        );
+        client_c.send(Message::text(r#"{"Ready": true}"#)).await;
+        time::sleep(Duration::from_millis(50)).await;
+        client_a.send(Message::text(r#"{"Ready": true}"#)).await;
+        match recv_peer_event(&mut client_a).await {
+            PeerEvent::StartGame { seat_order, .. } => assert_eq!(seat_order, vec![id_a, id_c]),
+            event => panic!("expected start, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn spectators() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?max=2&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+
+        // spectators don't take a seat, and players learn about them separately
+        let mut spectator = warp::test::ws()
+            .path("/room_a?role=spectator&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_s = recv_id_assigned(&mut spectator).await;
+        assert_eq!(recv_host_changed(&mut spectator).await, id_a);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewSpectator(id_s.clone())
+        );
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::NewSpectator(id_s.clone())
+        );
+        recv_host_changed(&mut client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        assert_eq!(recv_peer_event(&mut client_c).await, PeerEvent::RoomFull);
+
+        // signalling works both ways
+        spectator
+            .send(Message::text(format!(
+                r#"{{"Signal": {{"receiver": "{}", "data": "123"}}}}"#,
+                id_a
+            )))
             .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Signal {
+                sender: id_s.clone(),
+                data: serde_json::Value::String("123".to_string()),
+            }
+        );
+
+        // the game starts without the spectator, who gets to know when
+        spectator.send(Message::text(r#"{"Ready": true}"#)).await;
+        assert_eq!(recv_error_code(&mut spectator).await, ErrorCode::Spectator);
+        client_a.send(Message::text(r#"{"Ready": true}"#)).await;
+        time::sleep(Duration::from_millis(50)).await;
+        client_b.send(Message::text(r#"{"Ready": true}"#)).await;
+        for client in [&mut client_a, &mut client_b, &mut spectator] {
+            match recv_peer_event(client).await {
+                PeerEvent::StartGame { seat_order, .. } => {
+                    assert_eq!(seat_order, vec![id_a.clone(), id_b.clone()])
+                }
+                event => panic!("expected start, got {:?}", event),
+            }
+        }
+
+        drop(spectator);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_s)
+        );
+    }
+
+    #[tokio::test]
+    async fn spectate_next_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut spectator = warp::test::ws()
+            .path("/next_2?role=spectator&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_s = recv_id_assigned(&mut spectator).await;
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewSpectator(id_s.clone())
+        );
 
         let mut client_b = warp::test::ws()
-            .path("/room_a")
+            .path("/next_2?protocol=2")
             .handshake(api)
-            // .handshake(ws_echo())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::NewSpectator(id_s)
+        );
+
+        // the spectator comes along into the filled room
+        let seats = vec![id_a.clone(), id_b];
+        assert_eq!(recv_host_changed(&mut spectator).await, id_a);
+        assert_eq!(recv_seats(&mut spectator).await, seats);
+        recv_peer_event(&mut client_a).await;
+        assert_eq!(recv_host_changed(&mut client_a).await, id_a);
+        assert_eq!(recv_seats(&mut client_a).await, seats);
+    }
+
+    async fn recv_error_code(client: &mut WsClient) -> ErrorCode {
+        match recv_peer_event(client).await {
+            PeerEvent::Error { code, .. } => code,
+            event => panic!("expected error, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn error_unknown_receiver() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+        client_a
+            .send(Message::text(
+                r#"{"Signal": {"receiver": "uuid-gone", "data": "123"}}"#.to_string(),
+            ))
+            .await;
+
+        assert_eq!(
+            recv_error_code(&mut client_a).await,
+            ErrorCode::UnknownReceiver
+        );
+    }
+
+    #[tokio::test]
+    async fn error_duplicate_uuid() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
             .await
             .expect("handshake");
 
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+        client_a
+            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
+            .await;
+
+        assert_eq!(
+            recv_error_code(&mut client_a).await,
+            ErrorCode::DuplicateUuid
+        );
+
+        let mut client_b = warp::test::ws()
+            .path("/room_b")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
         client_b
             .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
             .await;
 
-        let a_msg = client_a.recv().await;
-        let new_peer_event: PeerEvent =
-            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
+        // Legacy clients can't take ids in use, whether picked or assigned
+        for id in ["uuid-b".to_string(), id_a] {
+            let mut client = warp::test::ws()
+                .path("/room_c")
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            client
+                .send(Message::text(format!("{{\"Uuid\": \"{}\"}}", id)))
+                .await;
+            assert!(client.recv_closed().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn error_malformed_json() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+        client_a
+            .send(Message::text(r#"{"Uuid": "#.to_string()))
+            .await;
+
+        assert_eq!(
+            recv_error_code(&mut client_a).await,
+            ErrorCode::MalformedRequest
+        );
+    }
 
-        assert_eq!(new_peer_event, PeerEvent::NewPeer("uuid-b".to_string()));
+    fn room_lifetime() -> RoomLifetime {
+        RoomLifetime {
+            empty_ttl: Duration::from_secs(60),
+            max_lifetime: None,
+            reap_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn id_room_peer(uuid: &str, room_id: &str) -> Peer {
+        Peer {
+            uuid: uuid.to_string(),
+            room: RequestedRoom::Id(room_id.to_string()),
+            matched_room: None,
+            version: None,
+            player: None,
+            rating: None,
+            max_wait: None,
+            min_players: None,
+            spectator: false,
+            ip: None,
+            connected_at: Instant::now(),
+            messages: Default::default(),
+            sender: None,
+            kick: None,
+            resume_token: None,
+            connection: 0,
+        }
+    }
+
+    #[test]
+    fn empty_queues_are_forgotten() {
+        let mut state = State::default();
+        let next_peer = |uuid: &str| Peer {
+            room: RequestedRoom::Next(2),
+            version: Some("1.0".to_string()),
+            ..id_room_peer(uuid, "")
+        };
+        state
+            .add_peer(next_peer("uuid-a"), &Default::default())
+            .unwrap();
+        state.remove_peer(&"uuid-a".to_string());
+        assert!(state.next_rooms.is_empty());
+
+        state
+            .add_peer(next_peer("uuid-b"), &Default::default())
+            .unwrap();
+        state
+            .add_peer(next_peer("uuid-c"), &Default::default())
+            .unwrap();
+        assert!(state.next_rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_version() {
+        let api = api();
+        for path in [
+            "/next_2?version=".to_string(),
+            "/next_2?version=1%200".to_string(),
+            format!("/next_2?version={}", "1".repeat(65)),
+        ] {
+            let rejected = warp::test::ws().path(&path).handshake(api.clone()).await;
+            assert!(rejected.is_err(), "{} was accepted", path);
+        }
+        let accepted = warp::test::ws()
+            .path("/next_2?version=1.2.3-beta%2B4&protocol=2")
+            .handshake(api)
+            .await;
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_room_expires() {
+        let state = Arc::new(Mutex::new(State::new(Config {
+            rooms: room_lifetime(),
+            ..Default::default()
+        })));
+        spawn_room_reaper(state.clone(), Duration::from_secs(1));
+
+        {
+            let mut state = state.lock().await;
+            state
+                .add_peer(id_room_peer("uuid-a", "room_a"), &Default::default())
+                .unwrap();
+            time::advance(Duration::from_secs(30)).await;
+            state.remove_peer(&"uuid-a".to_string());
+        }
+
+        // The ttl starts counting when the last peer leaves
+        time::sleep(Duration::from_secs(59)).await;
+        assert!(state.lock().await.id_rooms.contains_key("room_a"));
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert!(!state.lock().await.id_rooms.contains_key("room_a"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn occupied_room_is_kept() {
+        let state = Arc::new(Mutex::new(State::new(Config {
+            rooms: room_lifetime(),
+            ..Default::default()
+        })));
+        spawn_room_reaper(state.clone(), Duration::from_secs(1));
+
+        state
+            .lock()
+            .await
+            .add_peer(id_room_peer("uuid-a", "room_a"), &Default::default())
+            .unwrap();
+
+        time::sleep(Duration::from_secs(60 * 60)).await;
+        assert!(state.lock().await.id_rooms.contains_key("room_a"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn room_max_lifetime() {
+        let state = Arc::new(Mutex::new(State::new(Config {
+            rooms: RoomLifetime {
+                max_lifetime: Some(Duration::from_secs(10 * 60)),
+                ..room_lifetime()
+            },
+            ..Default::default()
+        })));
+        spawn_room_reaper(state.clone(), Duration::from_secs(1));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        state
+            .lock()
+            .await
+            .add_peer(
+                Peer {
+                    sender: Some(PeerSender {
+                        messages: sender,
+                        encoding: Encoding::Json,
+                        legacy: false,
+                    }),
+                    ..id_room_peer("uuid-a", "room_a")
+                },
+                &Default::default(),
+            )
+            .unwrap();
+
+        time::sleep(Duration::from_secs(9 * 60)).await;
+        assert!(state.lock().await.id_rooms.contains_key("room_a"));
+
+        time::sleep(Duration::from_secs(2 * 60)).await;
+        assert!(!state.lock().await.id_rooms.contains_key("room_a"));
+
+        let message = receiver.recv().await.unwrap().unwrap();
+        let event: PeerEvent = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert!(matches!(
+            event,
+            PeerEvent::Error {
+                code: ErrorCode::RoomExpired,
+                ..
+            }
+        ));
+        assert!(receiver.recv().await.unwrap().unwrap().is_close());
+
+        // The peer is no longer a member, so leaving doesn't bring the room back
+        state.lock().await.remove_peer(&"uuid-a".to_string());
+        assert!(!state.lock().await.id_rooms.contains_key("room_a"));
+    }
+
+    #[tokio::test]
+    async fn room_full() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?max=2&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_b).await;
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        assert_eq!(recv_peer_event(&mut client_c).await, PeerEvent::RoomFull);
+
+        // Once someone leaves, there is space again
+        drop(client_b);
+        recv_peer_event(&mut client_a).await;
+
+        let mut client_d = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_d).await;
+    }
+
+    #[tokio::test]
+    async fn server_shutdown() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(State::new(Config {
+            shutdown: Shutdown {
+                reconnect_after: Duration::from_secs(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        })));
+        let api = ws_filter(state.clone());
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
+        let mut client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id_assigned(&mut client_b).await;
+        recv_host_changed(&mut client_b).await;
+        recv_peer_event(&mut client_a).await;
+
+        state.lock().await.shutdown();
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::ServerShutdown {
+                    reconnect_after_ms: 2000
+                }
+            );
+        }
+
+        // handshakes that are going on can finish
+        client_a
+            .send_text(format!(
+                r#"{{"Signal": {{"receiver": "{}", "data": "123"}}}}"#,
+                id_b
+            ))
+            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::Signal {
+                sender: id_a,
+                data: serde_json::Value::String("123".to_string()),
+            }
+        );
+
+        // no new peers once shutting down
+        assert!(warp::test::ws()
+            .path("/room_b?protocol=2")
+            .handshake(api)
+            .await
+            .is_err());
+
+        // until the drain period is over
+        state.lock().await.close_connections();
+        for client in [&mut client_a, &mut client_b] {
+            client.recv_closed().await.unwrap();
+        }
+        drop(client_a);
+        drop(client_b);
+        time::timeout(Duration::from_secs(5), all_peers_left(state))
+            .await
+            .expect("peers are still connected");
+    }
+
+    #[tokio::test]
+    async fn match_by_version() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/next_2?version=1&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/next_2?version=2&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        recv_id_assigned(&mut client_b).await;
+
+        let mut client_c = warp::test::ws()
+            .path("/next_2?version=1&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        let id_c = recv_id_assigned(&mut client_c).await;
+
+        // a and c run the same version, b keeps waiting
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_c)
+        );
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
     }
 
     #[tokio::test]
-    async fn signal() {
+    async fn version_mismatch() {
         let _ = pretty_env_logger::try_init();
         let api = api();
 
-        // let req = warp::test::ws().path("/echo");
         let mut client_a = warp::test::ws()
-            .path("/room_a")
+            .path("/room_a?version=2&protocol=2")
             .handshake(api.clone())
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
 
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        recv_id_assigned(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
-            .path("/room_a")
+            .path("/room_a?version=1&protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::VersionMismatch {
+                room_version: Some("2".to_string())
+            }
+        );
+
+        // Clients from before versioning don't send one at all
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
             .handshake(api)
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
 
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::VersionMismatch {
+                room_version: Some("2".to_string())
+            }
+        );
+    }
 
-        let a_msg = client_a.recv().await;
-        let new_peer_event: PeerEvent =
-            serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
+    /// A MessagePack socket learns why it couldn't join
+    #[tokio::test]
+    async fn socket_close_reason() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::new(Config::default()));
 
-        let peer_uuid = match new_peer_event {
-            PeerEvent::NewPeer(peer) => peer,
-            _ => panic!("unexpected event"),
-        };
+        let mut client_a = connect_async(format!("ws://{}/room_a?max=1&protocol=2", addr))
+            .await
+            .unwrap()
+            .0;
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::IdAssigned(_)
+        ));
 
-        client_a
-            .send(Message::text(format!(
-                "{{\"Signal\": {{\"receiver\": \"{}\", \"data\": \"123\" }}}}",
-                peer_uuid
-            )))
-            .await;
+        let (mut socket, message_loop) = WebRtcSocket::new_with_encoding(
+            format!("ws://{}/room_a", addr),
+            SocketEncoding::MessagePack,
+        );
+        let message_loop = tokio::spawn(message_loop);
+        let close_reason = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(close_reason) = socket.close_reason() {
+                    return close_reason.clone();
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no close reason");
+        message_loop.abort();
+        assert_eq!(close_reason, CloseReason::RoomFull);
+    }
 
-        let b_msg = client_b.recv().await;
-        let signal_event: PeerEvent =
-            serde_json::from_str(b_msg.unwrap().to_str().unwrap()).unwrap();
+    /// Forwards connections to `addr`, the first one only until `cut` resolves
+    async fn flaky_proxy(addr: SocketAddr, cut: oneshot::Receiver<()>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut cut = Some(cut);
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let mut server = TcpStream::connect(addr).await.unwrap();
+                let cut = cut.take();
+                tokio::spawn(async move {
+                    let forward = tokio::io::copy_bidirectional(&mut client, &mut server);
+                    match cut {
+                        Some(cut) => select! {
+                            _ = forward => {}
+                            _ = cut => {}
+                        },
+                        None => {
+                            let _ = forward.await;
+                        }
+                    }
+                });
+            }
+        });
+        proxy_addr
+    }
+
+    /// The socket resumes its session when its connection breaks, rather than joining
+    /// as a new peer
+    #[tokio::test]
+    async fn socket_resumes() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::new(Config::default()));
+        let (cut, cut_rx) = oneshot::channel();
+        let proxy_addr = flaky_proxy(addr, cut_rx).await;
+
+        let mut client_a = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap()
+            .0;
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::IdAssigned(_)
+        ));
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::ResumeToken(_)
+        ));
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::HostChanged(_)
+        ));
 
+        let (mut socket, message_loop) = WebRtcSocket::new(format!("ws://{}/room_a", proxy_addr));
+        let message_loop = tokio::spawn(message_loop);
+        let id = socket.id().await;
         assert_eq!(
-            signal_event,
-            PeerEvent::Signal {
-                data: serde_json::Value::String("123".to_string()),
-                sender: "uuid-a".to_string(),
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(id.clone())
+        );
+        while socket.resume_token().is_none() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        cut.send(()).unwrap();
+        let event = time::timeout(Duration::from_secs(5), next_event(&mut client_a))
+            .await
+            .expect("socket didn't resume");
+        message_loop.abort();
+        assert_eq!(event, PeerEvent::PeerReconnected(id));
+    }
+
+    fn serve(state: State) -> SocketAddr {
+        let (addr, server) =
+            warp::serve(ws_filter(Arc::new(Mutex::new(state)))).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Reads the next event, answering any pings on the way
+    async fn next_event(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> PeerEvent {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                tungstenite::Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                tungstenite::Message::Ping(_) => continue,
+                message => panic!("unexpected message {:?}", message),
             }
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::new(Config {
+            keepalive: Keepalive {
+                ping_interval: Duration::from_millis(50),
+                pong_timeout: Duration::from_millis(100),
+                idle_timeout: Duration::from_secs(60),
+                resume_grace: Duration::ZERO,
+            },
+            ..Default::default()
+        }));
+
+        let (mut client_a, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_a = match next_event(&mut client_a).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+
+        let (mut client_b, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_b = match next_event(&mut client_b).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::HostChanged(id_a)
+        );
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
         );
+
+        // b stops reading, so it never answers pings, while a keeps answering them
+        assert_eq!(next_event(&mut client_a).await, PeerEvent::PeerLeft(id_b));
+
+        // b's connection was closed, its client fails answering the queued pings
+        while let Some(Ok(_)) = client_b.next().await {}
+
+        // a keeps answering pings while nothing else happens, and stays connected
+        let next = time::timeout(Duration::from_millis(300), next_event(&mut client_a)).await;
+        assert!(next.is_err());
+        client_a
+            .send(tungstenite::Message::Text(
+                r#"{"Signal": {"receiver": "nobody", "data": "123"}}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::Error {
+                code: ErrorCode::UnknownReceiver,
+                ..
+            }
+        ));
     }
 
-    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
-        let message = client.recv().await;
-        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
+    #[tokio::test]
+    async fn idle_timeout() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::new(Config {
+            keepalive: Keepalive {
+                ping_interval: Duration::from_secs(60),
+                pong_timeout: Duration::from_secs(60),
+                idle_timeout: Duration::from_millis(100),
+                resume_grace: Duration::ZERO,
+            },
+            ..Default::default()
+        }));
+
+        // a legacy client that never picks its id
+        let (mut client_a, _) = connect_async(format!("ws://{}/room_a", addr))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client_a.next().await.unwrap().unwrap(),
+            tungstenite::Message::Close(_)
+        ));
     }
 
     #[tokio::test]
-    async fn match_pairs() {
+    async fn pongs_keep_peers_alive() {
         let _ = pretty_env_logger::try_init();
-        let api = api();
+        let addr = serve(State::new(Config {
+            keepalive: Keepalive {
+                ping_interval: Duration::from_millis(20),
+                pong_timeout: Duration::from_millis(100),
+                idle_timeout: Duration::from_millis(100),
+                resume_grace: Duration::ZERO,
+            },
+            ..Default::default()
+        }));
+
+        let (mut client_a, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::IdAssigned(_)
+        ));
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::HostChanged(_)
+        ));
+
+        // reading answers every ping, but a never sends a request
+        let mut pings = 0;
+        let quiet = time::timeout(Duration::from_millis(300), async {
+            loop {
+                match client_a.next().await.unwrap().unwrap() {
+                    tungstenite::Message::Ping(_) => pings += 1,
+                    message => panic!("unexpected message {:?}", message),
+                }
+            }
+        })
+        .await;
+        assert!(quiet.is_err());
+        assert!(pings > 1);
+
+        // a is still in its room
+        let (_client_b, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_limits() {
+        let _ = pretty_env_logger::try_init();
+        let api = ws_filter(Arc::new(Mutex::new(State::new(Config {
+            max_rooms: Some(1),
+            max_peers_per_room: Some(2),
+            ..Default::default()
+        }))));
 
         let mut client_a = warp::test::ws()
-            .path("/next_2")
+            .path("/room_a?max=3&protocol=2")
             .handshake(api.clone())
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        recv_id_assigned(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
-            .path("/next_2")
+            .path("/room_a?protocol=2")
             .handshake(api.clone())
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
+        recv_id_assigned(&mut client_b).await;
 
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
-
+        // the requested capacity is capped by max_peers_per_room
         let mut client_c = warp::test::ws()
-            .path("/next_2")
+            .path("/room_a?protocol=2")
             .handshake(api.clone())
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
-
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
+        assert_eq!(recv_peer_event(&mut client_c).await, PeerEvent::RoomFull);
 
         let mut client_d = warp::test::ws()
-            .path("/next_2")
+            .path("/room_b?protocol=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        assert_eq!(
+            recv_error_code(&mut client_d).await,
+            ErrorCode::TooManyRooms
+        );
+
+        let mut client_e = warp::test::ws()
+            .path("/next_3?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(recv_error_code(&mut client_e).await, ErrorCode::InvalidRoom);
+    }
+
+    #[tokio::test]
+    async fn rate_limits() {
+        let _ = pretty_env_logger::try_init();
+        let api = ws_filter(Arc::new(Mutex::new(State::new(Config {
+            rate_limits: RateLimits {
+                messages_per_second: 1.,
+                burst: 2,
+                max_connections_per_ip: 1,
+                max_message_size: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        }))));
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?protocol=2")
             .handshake(api.clone())
-            // .handshake(ws_echo())
             .await
             .expect("handshake");
+        recv_id_assigned(&mut client_a).await;
+        recv_host_changed(&mut client_a).await;
 
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
+        // only one connection per ip
+        let client_b = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api.clone())
             .await;
+        assert!(client_b.is_err());
 
-        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
-        let new_peer_b = recv_peer_event(&mut client_a).await;
-        let new_peer_d = recv_peer_event(&mut client_c).await;
+        client_a.send_text("x".repeat(150)).await;
+        assert_eq!(
+            recv_error_code(&mut client_a).await,
+            ErrorCode::MessageTooLarge
+        );
 
-        assert_eq!(new_peer_b, PeerEvent::NewPeer("uuid-b".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        // flooding gets throttled, then disconnected
+        for _ in 0..20 {
+            client_a
+                .send_text(r#"{"Signal": {"receiver": "nobody", "data": "123"}}"#)
+                .await;
+        }
+        let mut unknown_receivers = 0;
+        loop {
+            match recv_error_code(&mut client_a).await {
+                ErrorCode::UnknownReceiver => unknown_receivers += 1,
+                ErrorCode::Throttled => {}
+                ErrorCode::RateLimited => break,
+                code => panic!("unexpected error {:?}", code),
+            }
+        }
+        assert_eq!(unknown_receivers, 1);
+        assert!(client_a.recv_closed().await.is_ok());
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = client_d.recv() => panic!("unexpected message"),
-            _ = &mut timeout => {}
+        // the connection no longer counts towards the limit
+        let mut client_c = warp::test::ws()
+            .path("/room_a?protocol=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id_assigned(&mut client_c).await;
+    }
+
+    #[tokio::test]
+    async fn resume_session() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::default());
+
+        let (mut client_a, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_a = match next_event(&mut client_a).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::ResumeToken(_)
+        ));
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+
+        let (mut client_b, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_b = match next_event(&mut client_b).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        let token_b = match next_event(&mut client_b).await {
+            PeerEvent::ResumeToken(token) => token,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        // b loses its connection without closing it
+        drop(client_b);
+        time::sleep(Duration::from_millis(50)).await;
+
+        // the room in the path doesn't matter when resuming
+        let (mut client_b, _) = connect_async(format!(
+            "ws://{}/room_b?resume={}&protocol=2",
+            addr, token_b
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::IdAssigned(id_b.clone())
+        );
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::PeerReconnected(id_b.clone())
+        );
+
+        // signals reach the new connection
+        client_a
+            .send(tungstenite::Message::Text(format!(
+                r#"{{"Signal": {{"receiver": "{}", "data": "123"}}}}"#,
+                id_b
+            )))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::Signal {
+                sender: id_a,
+                data: serde_json::Value::String("123".to_string()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_in_queue() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::default());
+
+        let mut clients = vec![];
+        let mut ids = vec![];
+        let mut tokens = vec![];
+        for _ in 0..2 {
+            let (mut client, _) = connect_async(format!("ws://{}/next_3?protocol=2", addr))
+                .await
+                .unwrap();
+            ids.push(match next_event(&mut client).await {
+                PeerEvent::IdAssigned(id) => id,
+                event => panic!("unexpected event {:?}", event),
+            });
+            tokens.push(match next_event(&mut client).await {
+                PeerEvent::ResumeToken(token) => token,
+                event => panic!("unexpected event {:?}", event),
+            });
+            clients.push(client);
+        }
+        let (mut client_b, mut client_a) = (clients.pop().unwrap(), clients.pop().unwrap());
+        let (id_a, id_b) = (ids[0].clone(), ids[1].clone());
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        // a loses its connection, and its place in the queue until it resumes
+        drop(client_a);
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::PeerLeft(id_a.clone())
+        );
+
+        // so the room doesn't fill up without it
+        let (mut client_c, _) = connect_async(format!("ws://{}/next_3?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_c = match next_event(&mut client_c).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert!(matches!(
+            next_event(&mut client_c).await,
+            PeerEvent::ResumeToken(_)
+        ));
+        assert_eq!(
+            next_event(&mut client_b).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+
+        // a queues again when it resumes, which fills the room
+        let (mut client_a, _) = connect_async(format!(
+            "ws://{}/next_3?resume={}&protocol=2",
+            addr, tokens[0]
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::IdAssigned(id_a.clone())
+        );
+        for client in [&mut client_b, &mut client_c] {
+            assert_eq!(next_event(client).await, PeerEvent::NewPeer(id_a.clone()));
+        }
+        for client in [&mut client_a, &mut client_b, &mut client_c] {
+            assert_eq!(
+                next_event(client).await,
+                PeerEvent::HostChanged(id_a.clone())
+            );
+            assert_eq!(
+                next_event(client).await,
+                PeerEvent::Seats(vec![id_a.clone(), id_b.clone(), id_c.clone()])
+            );
         }
     }
 
+    #[tokio::test]
+    async fn resume_grace_expires() {
+        let _ = pretty_env_logger::try_init();
+        let addr = serve(State::new(Config {
+            keepalive: Keepalive {
+                resume_grace: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+
+        let (mut client_a, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_a = match next_event(&mut client_a).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert!(matches!(
+            next_event(&mut client_a).await,
+            PeerEvent::ResumeToken(_)
+        ));
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::HostChanged(id_a.clone())
+        );
+
+        let (mut client_b, _) = connect_async(format!("ws://{}/room_a?protocol=2", addr))
+            .await
+            .unwrap();
+        let id_b = match next_event(&mut client_b).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        let token_b = match next_event(&mut client_b).await {
+            PeerEvent::ResumeToken(token) => token,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        // b doesn't come back in time
+        drop(client_b);
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_b.clone())
+        );
+
+        // the token is no longer valid, b joins as a new peer
+        let (mut client_b, _) = connect_async(format!(
+            "ws://{}/room_a?resume={}&protocol=2",
+            addr, token_b
+        ))
+        .await
+        .unwrap();
+        let new_id_b = match next_event(&mut client_b).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_ne!(new_id_b, id_b);
+        assert_eq!(
+            next_event(&mut client_a).await,
+            PeerEvent::NewPeer(new_id_b)
+        );
+    }
+
     #[test]
     fn requested_room() {
-        assert_eq!(parse_room_id("next_2".into()), RequestedRoom::Next(2));
+        assert_eq!(
+            parse_room_request("next_2".into(), Default::default()).room,
+            RequestedRoom::Next(2)
+        );
+        assert_eq!(
+            parse_room_request("ABCDE".into(), Default::default()).room,
+            RequestedRoom::Id("ABCDE".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn room_options() {
+        let options: RoomOptions = warp::test::request()
+            .path("/ABCDE?max=2")
+            .filter(&warp::query::<RoomOptions>())
+            .await
+            .unwrap();
+        assert_eq!(options.max, NonZeroUsize::new(2));
+
+        let options: RoomOptions = warp::test::request()
+            .path("/next_4?max_wait=30s&min_players=2")
+            .filter(&warp::query::<RoomOptions>())
+            .await
+            .unwrap();
+        assert_eq!(options.max_wait, Some(Duration::from_secs(30)));
+        assert_eq!(options.min_players, NonZeroUsize::new(2));
+
+        let options: RoomOptions = warp::test::request()
+            .path("/ABCDE?resume=abc")
+            .filter(&warp::query::<RoomOptions>())
+            .await
+            .unwrap();
+        assert_eq!(options.resume, Some("abc".to_string()));
+        assert!(options.legacy());
+
+        let options: RoomOptions = warp::test::request()
+            .path("/ABCDE?protocol=2")
+            .filter(&warp::query::<RoomOptions>())
+            .await
+            .unwrap();
+        assert!(!options.legacy());
+
+        // A room for zero players makes no sense
+        let api = api();
+        let rejected = warp::test::ws()
+            .path("/ABCDE?max=0&protocol=2")
+            .handshake(api)
+            .await;
+        assert!(rejected.is_err());
     }
 }
@@ -0,0 +1,306 @@
+use log::{error, info, warn};
+use std::{
+    convert::Infallible,
+    fs::File,
+    future::Future,
+    io,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::{
+    hyper::{
+        self,
+        server::accept,
+        service::{make_service_fn, service_fn, Service},
+    },
+    Filter, Reply,
+};
+
+use crate::config::Tls;
+
+/// Address of the client, for connections that are accepted by us instead of warp.
+/// Added to the request extensions, see [`crate::rate_limit::client_addr`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RemoteAddr(pub SocketAddr);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum TlsError {
+    #[error("could not read {0:?}: {1}")]
+    Read(Box<Path>, #[source] io::Error),
+    #[error("no certificate found in {0:?}")]
+    NoCertificate(Box<Path>),
+    #[error("no private key found in {0:?}")]
+    NoKey(Box<Path>),
+    #[error("invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// The TLS configuration currently in use, can be reloaded while the server is running
+#[derive(Clone)]
+pub(crate) struct Certificates {
+    tls: Tls,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl Certificates {
+    pub fn load(tls: Tls) -> Result<Self, TlsError> {
+        let config = load_server_config(&tls)?;
+        Ok(Certificates {
+            tls,
+            config: Arc::new(RwLock::new(config)),
+        })
+    }
+
+    /// Reads the certificate and key again. The previous ones are kept if that fails
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let config = load_server_config(&self.tls)?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+}
+
+fn load_server_config(tls: &Tls) -> Result<Arc<ServerConfig>, TlsError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| TlsError::Read(path.into(), e))
+    };
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(&tls.cert)?)
+        .map_err(|e| TlsError::Read(tls.cert.as_path().into(), e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(tls.cert.as_path().into()));
+    }
+
+    let mut key_file = open(&tls.key)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_file)
+            .map_err(|e| TlsError::Read(tls.key.as_path().into(), e))?
+        {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(TlsError::NoKey(tls.key.as_path().into())),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Reloads the certificates whenever the process receives SIGHUP
+#[cfg(unix)]
+pub(crate) fn spawn_reload_on_sighup(certificates: Certificates) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match certificates.reload() {
+                Ok(()) => info!("Reloaded TLS certificate"),
+                Err(e) => error!(
+                    "Failed to reload TLS certificate, keeping the old one: {}",
+                    e
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// How long to wait before accepting again after an error, like hyper's `AddrIncoming`
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Errors that concern a single connection rather than the listener
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Serves `filter` over TLS to connections accepted on `listener`, until `shutdown`
+/// resolves and in-flight requests are done
+pub(crate) async fn serve<F>(
+    filter: F,
+    listener: TcpListener,
+    certificates: Certificates,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let (connection_sender, connections) = mpsc::unbounded_channel();
+    let accept_task = tokio::spawn(async move {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(connection) => connection,
+                // the client gave up already, the next one is unaffected
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    // e.g. too many open files, retrying right away would spin
+                    error!("Error accepting connection: {:?}", e);
+                    time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            // Handshakes run on their own, so slow clients don't hold up everyone else
+            let acceptor = certificates.acceptor();
+            let handshake_timeout = certificates.tls.handshake_timeout;
+            let connection_sender = connection_sender.clone();
+            tokio::spawn(async move {
+                match time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = connection_sender.send(Ok::<_, io::Error>(stream));
+                    }
+                    Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", remote, e),
+                    Err(_) => warn!("TLS handshake with {} timed out", remote),
+                }
+            });
+        }
+    });
+
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+        let remote = stream.get_ref().0.peer_addr().ok().map(RemoteAddr);
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request| {
+                if let Some(remote) = remote {
+                    request.extensions_mut().insert(remote);
+                }
+                service.clone().call(request)
+            }))
+        }
+    });
+
+    let result = hyper::Server::builder(accept::from_stream(UnboundedReceiverStream::new(
+        connections,
+    )))
+    .serve(make_service)
+    .with_graceful_shutdown(shutdown)
+    .await;
+    accept_task.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use matchbox_socket::{SignallingOptions, WebRtcSocket};
+    use std::{fs, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        time,
+    };
+    use tokio_rustls::rustls::Certificate;
+
+    use crate::{
+        config::{Config, Tls},
+        signaling::{ws_filter, State},
+        tls::{serve, Certificates},
+    };
+
+    /// Writes a new self-signed certificate for localhost, returns the paths and the DER
+    /// encoded certificate
+    fn self_signed(dir: &Path) -> (Tls, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = Tls {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            handshake_timeout: Duration::from_millis(200),
+        };
+        fs::write(&tls.cert, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&tls.key, cert.serialize_private_key_pem()).unwrap();
+        (tls, Certificate(cert.serialize_der().unwrap()))
+    }
+
+    /// Connects a socket trusting only `trusted`, returns whether it got an id
+    async fn connect(addr: SocketAddr, trusted: &Certificate) -> bool {
+        let (mut socket, message_loop) = WebRtcSocket::new_with_options(
+            format!("wss://localhost:{}/room_a", addr.port()),
+            SignallingOptions {
+                root_certificates: vec![trusted.0.clone()],
+                ..Default::default()
+            },
+        );
+        // the message loop gives up if the handshake fails
+        let mut message_loop = tokio::spawn(message_loop);
+        let connected = tokio::select! {
+            biased;
+            _ = &mut message_loop => false,
+            _ = socket.id() => true,
+        };
+        message_loop.abort();
+        connected
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let _ = pretty_env_logger::try_init();
+        let dir = std::env::temp_dir().join(format!("matchbox-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (tls, first_cert) = self_signed(&dir);
+
+        let certificates = Certificates::load(tls).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new(Config::default())));
+        tokio::spawn(serve(
+            ws_filter(state),
+            listener,
+            certificates.clone(),
+            futures::future::pending(),
+        ));
+
+        assert!(connect(addr, &first_cert).await);
+
+        // a new certificate is only used after reloading
+        let (_, second_cert) = self_signed(&dir);
+        assert!(!connect(addr, &second_cert).await);
+        certificates.reload().unwrap();
+        assert!(connect(addr, &second_cert).await);
+        assert!(!connect(addr, &first_cert).await);
+
+        // broken files keep the current certificate
+        fs::write(dir.join("key.pem"), "").unwrap();
+        assert!(certificates.reload().is_err());
+        assert!(connect(addr, &second_cert).await);
+
+        // clients that never start the handshake are dropped
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        let closed = time::timeout(Duration::from_secs(5), stalled.read(&mut [0; 1])).await;
+        assert!(matches!(closed, Ok(Ok(0))));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}